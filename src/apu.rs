@@ -1,10 +1,32 @@
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::Path;
 
-use super::dsp::dsp::Dsp;
+use super::dsp::dsp::{Dsp, DspMemory, SAMPLE_RATE};
+use super::dsp::voice::ResamplingMode;
+use super::resampler::Resampler;
 use super::smp::Smp;
-use super::spc::spc::{Spc, IPL_ROM_LEN, RAM_LEN};
+#[cfg(feature = "std")]
+use super::smp::SmpRegisters;
+#[cfg(feature = "std")]
+use super::spc::spc::Spc;
+use super::state::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 use super::timer::Timer;
 
+pub const RAM_LEN: usize = 0x10000;
+pub const IPL_ROM_LEN: usize = 64;
+
+/// The range of output rates `set_output_sample_rate` accepts, in Hz.
+pub const MIN_OUTPUT_SAMPLE_RATE: u32 = 1000;
+pub const MAX_OUTPUT_SAMPLE_RATE: u32 = 384000;
+
 const DEFAULT_IPL_ROM: [u8; IPL_ROM_LEN] = [
     0xcd, 0xef, 0xbd, 0xe8, 0x00, 0xc6, 0x1d, 0xd0, 0xfc, 0x8f, 0xaa, 0xf4, 0x8f, 0xbb, 0xf5, 0x78,
     0xcc, 0xf4, 0xd0, 0xfb, 0x2f, 0x19, 0xeb, 0xf4, 0xd0, 0xfc, 0x7e, 0xf4, 0xd0, 0x0b, 0xe4, 0xf5,
@@ -12,50 +34,180 @@ const DEFAULT_IPL_ROM: [u8; IPL_ROM_LEN] = [
     0xf6, 0xda, 0x00, 0xba, 0xf4, 0xc4, 0xf4, 0xdd, 0x5d, 0xd0, 0xdb, 0x1f, 0x00, 0x00, 0xc0, 0xff,
 ];
 
-pub struct Apu<'a> {
+const DEFAULT_TEST_REG: u8 = 0x0a;
+
+pub const NUM_PORTS: usize = 4;
+
+const SAMPLES_BATCH_LEN: usize = 256;
+
+const LOOP_DETECTION_BLOCK_LEN: usize = 32;
+
+const ACCURATE_TIMING_SLICE_CYCLES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingMode {
+    /// Runs the SMP in large batches and lets the DSP catch up afterwards.
+    Fast,
+    /// Lets the DSP catch up every few cycles, so register writes and sample generation
+    /// interleave the way they do on hardware.
+    Accurate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    Full,
+    Mono,
+    /// Blends each channel towards the mono mix; 0.0 is full stereo and 1.0 is mono.
+    Narrow(f32),
+}
+
+impl StereoMode {
+    fn apply(self, sample: (i16, i16)) -> (i16, i16) {
+        let (left, right) = sample;
+        match self {
+            StereoMode::Full => sample,
+            StereoMode::Mono => {
+                let mid = (((left as i32) + (right as i32)) / 2) as i16;
+                (mid, mid)
+            }
+            StereoMode::Narrow(width) => {
+                let left = left as f32;
+                let right = right as f32;
+                let mid = (left + right) / 2.0;
+                (
+                    (left + (mid - left) * width) as i16,
+                    (right + (mid - right) * width) as i16,
+                )
+            }
+        }
+    }
+}
+
+/// What ARAM holds when the APU powers up or resets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PowerOnPattern {
+    Zero,
+    /// Runs of 32 $00 bytes alternating with 32 $FF bytes, which is what most consoles
+    /// come up with.
+    HighLow,
+    Custom(Box<[u8; RAM_LEN]>),
+}
+
+impl PowerOnPattern {
+    fn fill(&self, ram: &mut [u8; RAM_LEN]) {
+        match *self {
+            PowerOnPattern::Zero => *ram = [0; RAM_LEN],
+            PowerOnPattern::HighLow => {
+                for (i, value) in ram.iter_mut().enumerate() {
+                    *value = if (i & 0x20) == 0 { 0x00 } else { 0xff };
+                }
+            }
+            PowerOnPattern::Custom(ref pattern) => *ram = **pattern,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStateError {
+    /// The data doesn't start with a save state header.
+    BadMagic,
+    /// The state was saved by a different, incompatible version of this crate.
+    UnsupportedVersion(u8),
+    /// The data ended before the state did.
+    Truncated,
+    /// A field held a value no save state could have.
+    Invalid,
+}
+
+impl fmt::Display for LoadStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadStateError::BadMagic => write!(f, "not an APU save state"),
+            LoadStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            LoadStateError::Truncated => write!(f, "save state is truncated"),
+            LoadStateError::Invalid => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LoadStateError {}
+
+pub struct Apu {
     ram: Box<[u8; RAM_LEN]>,
-    ipl_rom: &'a [u8; IPL_ROM_LEN],
+    echo_sandbox: Option<Box<[u8; RAM_LEN]>>,
+    ipl_rom: [u8; IPL_ROM_LEN],
+    power_on_pattern: PowerOnPattern,
 
-    pub smp: Smp<'a>,
-    pub dsp: Dsp<'a>,
+    pub smp: Smp,
+    pub dsp: Dsp,
 
     timers: [Timer; 3],
 
+    resampler: Resampler,
+    stereo_mode: StereoMode,
+    timing_mode: TimingMode,
+
     is_ipl_rom_enabled: bool,
     dsp_reg_address: u8,
+    cpu_ports: [u8; NUM_PORTS],
+    smp_ports: [u8; NUM_PORTS],
+
+    test_reg: u8,
+    are_timers_enabled: bool,
+    is_ram_write_enabled: bool,
+
+    samples_rendered: u64,
 }
 
-impl<'apu> Apu<'apu> {
-    pub fn new() -> Rc<Apu<'apu>> {
-        Rc::new_cyclic(|ptr| Apu {
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
             ram: Box::new([0; RAM_LEN]),
-            ipl_rom: &DEFAULT_IPL_ROM,
+            echo_sandbox: None,
+            ipl_rom: DEFAULT_IPL_ROM,
+            power_on_pattern: PowerOnPattern::Zero,
 
-            smp: Smp::new(ptr.clone()),
-            dsp: Dsp::new(ptr.clone()),
+            smp: Smp::new(),
+            dsp: Dsp::new(),
 
             timers: [Timer::new(256), Timer::new(256), Timer::new(32)],
 
+            resampler: Resampler::new(SAMPLE_RATE as u32),
+            stereo_mode: StereoMode::Full,
+            timing_mode: TimingMode::Fast,
+
             is_ipl_rom_enabled: true,
             dsp_reg_address: 0,
-        })
+            cpu_ports: [0; NUM_PORTS],
+            smp_ports: [0; NUM_PORTS],
+
+            test_reg: DEFAULT_TEST_REG,
+            are_timers_enabled: true,
+            is_ram_write_enabled: true,
+
+            samples_rendered: 0,
+        }
     }
 
-    pub fn from_spc(spc: &Spc) -> Rc<Apu> {
+    #[cfg(feature = "std")]
+    pub fn from_spc(spc: &Spc) -> Apu {
         let mut ret = Apu::new();
 
         *ret.ram = spc.ram;
 
-        *ret.ipl_rom = spc.ipl_rom;
+        ret.ipl_rom = spc.ipl_rom;
 
-        {
-            ret.smp.reg_pc = spc.pc;
-            ret.smp.reg_a = spc.a;
-            ret.smp.reg_x = spc.x;
-            ret.smp.reg_y = spc.y;
-            ret.smp.set_psw(spc.psw);
-            ret.smp.reg_sp = spc.sp;
-        }
+        ret.smp.set_registers(SmpRegisters {
+            pc: spc.pc,
+            a: spc.a,
+            x: spc.x,
+            y: spc.y,
+            sp: spc.sp,
+            psw: spc.psw,
+        });
 
         ret.dsp.set_state(spc);
 
@@ -67,25 +219,467 @@ impl<'apu> Apu<'apu> {
         ret.set_control_reg(control_reg);
 
         ret.dsp_reg_address = ret.ram[0xf2];
+        for i in 0..NUM_PORTS {
+            ret.cpu_ports[i] = ret.ram[0xf4 + i];
+            ret.smp_ports[i] = ret.ram[0xf4 + i];
+        }
 
         ret
     }
 
+    /// Loads an SPC file and boots an APU from it in one go.
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Apu> {
+        let spc = Spc::load(path)?;
+        Ok(Apu::from_spc(&spc))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_spc(&self) -> Spc {
+        let mut ram = *self.ram;
+
+        // The I/O registers aren't backed by RAM, so fold their current state back in
+        //  the same way from_spc expects to find it.
+        ram[0xf0] = self.test_reg;
+        ram[0xf1] = self.get_control_reg();
+        ram[0xf2] = self.dsp_reg_address;
+        ram[0xf4..0xf4 + NUM_PORTS].copy_from_slice(&self.cpu_ports);
+        for (i, timer) in self.timers.iter().enumerate() {
+            ram[0xfa + i] = timer.target().unwrap_or(0);
+        }
+
+        Spc {
+            version_minor: 30,
+            pc: self.smp.reg_pc,
+            a: self.smp.reg_a,
+            x: self.smp.reg_x,
+            y: self.smp.reg_y,
+            psw: self.smp.get_psw(),
+            sp: self.smp.reg_sp,
+            id666_tag: None,
+            ram,
+            regs: self.dsp.get_state(),
+            ipl_rom: self.ipl_rom,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.power_on_pattern.fill(&mut self.ram);
+        if let Some(ref mut sandbox) = self.echo_sandbox {
+            **sandbox = [0; RAM_LEN];
+        }
+        self.ipl_rom = DEFAULT_IPL_ROM;
+
+        self.smp.reset();
+        self.dsp.reset();
+
+        self.timers = [Timer::new(256), Timer::new(256), Timer::new(32)];
+
+        self.resampler = Resampler::new(self.resampler.output_sample_rate());
+
+        self.is_ipl_rom_enabled = true;
+        self.dsp_reg_address = 0;
+        self.cpu_ports = [0; NUM_PORTS];
+        self.smp_ports = [0; NUM_PORTS];
+
+        self.test_reg = DEFAULT_TEST_REG;
+        self.are_timers_enabled = true;
+        self.is_ram_write_enabled = true;
+
+        self.samples_rendered = 0;
+    }
+
+    pub fn power_on_ram_pattern(&self) -> &PowerOnPattern {
+        &self.power_on_pattern
+    }
+
+    /// Fills RAM with `fill` right away, and again on every `reset`. Defaults to `Zero` so
+    /// runs are reproducible; some copy protection checks expect `HighLow`.
+    pub fn set_power_on_ram_pattern(&mut self, fill: PowerOnPattern) {
+        fill.fill(&mut self.ram);
+        self.power_on_pattern = fill;
+    }
+
+    /// Captures the complete emulation state, including things an SPC snapshot can't hold
+    /// like envelope phases, timer dividers and the echo buffer position. Loading it back
+    /// with `load_state` carries on exactly where this left off. Settings like the stereo
+    /// mode, muting and debugging hooks aren't included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(STATE_MAGIC);
+        writer.write_u8(STATE_VERSION);
+
+        self.save_emulation_state(&mut writer);
+        self.resampler.save_state(&mut writer);
+        writer.write_u64(self.samples_rendered);
+
+        writer.into_bytes()
+    }
+
+    // Everything that decides what the emulated hardware does next. The resampler and the
+    //  sample count only affect how its output is handed back, so they're saved separately.
+    fn save_emulation_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram[..]);
+        writer.write_bytes(&self.ipl_rom);
+
+        self.smp.save_state(writer);
+        self.dsp.save_state(writer);
+        for timer in self.timers.iter() {
+            timer.save_state(writer);
+        }
+
+        writer.write_bool(self.is_ipl_rom_enabled);
+        writer.write_u8(self.dsp_reg_address);
+        writer.write_u8(self.test_reg);
+        writer.write_bytes(&self.cpu_ports);
+        writer.write_bytes(&self.smp_ports);
+    }
+
+    /// Restores a state captured by `save_state`, including the output sample rate it was
+    /// saved with. If this fails partway through, the APU is left in an unspecified state
+    /// and should be reset or loaded again.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), LoadStateError> {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 4];
+        reader
+            .read_bytes(&mut magic)
+            .map_err(|_| LoadStateError::BadMagic)?;
+        if &magic != STATE_MAGIC {
+            return Err(LoadStateError::BadMagic);
+        }
+        let version = reader.read_u8()?;
+        if version != STATE_VERSION {
+            return Err(LoadStateError::UnsupportedVersion(version));
+        }
+
+        reader.read_bytes(&mut self.ram[..])?;
+        reader.read_bytes(&mut self.ipl_rom)?;
+
+        self.smp.load_state(&mut reader)?;
+        self.dsp.load_state(&mut reader)?;
+        for timer in self.timers.iter_mut() {
+            timer.load_state(&mut reader)?;
+        }
+
+        self.is_ipl_rom_enabled = reader.read_bool()?;
+        self.dsp_reg_address = reader.read_u8()?;
+        let test_reg = reader.read_u8()?;
+        self.set_test_reg(test_reg);
+        reader.read_bytes(&mut self.cpu_ports)?;
+        reader.read_bytes(&mut self.smp_ports)?;
+
+        self.resampler.load_state(&mut reader)?;
+        self.samples_rendered = reader.read_u64()?;
+        Ok(())
+    }
+
+    pub fn output_sample_rate(&self) -> u32 {
+        self.resampler.output_sample_rate()
+    }
+
+    /// Sets the rate `render` and friends produce frames at. The DSP itself always runs at
+    /// 32000 Hz; anything else is linearly resampled from that. Rates outside
+    /// `MIN_OUTPUT_SAMPLE_RATE..=MAX_OUTPUT_SAMPLE_RATE` are clamped into that range.
+    pub fn set_output_sample_rate(&mut self, hz: u32) {
+        let hz = hz.clamp(MIN_OUTPUT_SAMPLE_RATE, MAX_OUTPUT_SAMPLE_RATE);
+        self.resampler = Resampler::new(hz);
+    }
+
+    pub fn stereo_mode(&self) -> StereoMode {
+        self.stereo_mode
+    }
+
+    /// Sets how the DSP's stereo output is mixed down on its way out of `render`. This
+    /// doesn't affect the DSP itself.
+    pub fn set_stereo_mode(&mut self, mode: StereoMode) {
+        self.stereo_mode = mode;
+    }
+
+    pub fn timing_mode(&self) -> TimingMode {
+        self.timing_mode
+    }
+
+    pub fn set_timing_mode(&mut self, mode: TimingMode) {
+        self.timing_mode = mode;
+    }
+
+    /// Fills `buffer` with frames at the output rate. This runs straight through SMP
+    /// breakpoints and watchpoints; use `run` or `step` to stop on them.
     pub fn render(&mut self, buffer: &mut [(i16, i16)]) {
-        while self.dsp.output_buffer.len() < buffer.len() {
-            self.smp.run(buffer.len() * 64);
-            self.dsp.flush();
+        self.render_frames(buffer, 1, |out, sample| out[0] = sample);
+    }
+
+    /// Fills exactly `buffer.len()` frames, including none at all. Whatever the DSP
+    /// produces past that stays buffered for the next call, so consecutive calls pick up
+    /// where the last one left off regardless of buffer size. `render` behaves the same
+    /// way; this spells the guarantee out for callers that depend on it.
+    pub fn render_exact(&mut self, buffer: &mut [(i16, i16)]) {
+        self.render(buffer);
+    }
+
+    /// Renders `seconds` worth of frames at the output rate into a freshly allocated
+    /// buffer, rounding to the nearest frame.
+    pub fn render_seconds(&mut self, seconds: f32) -> Vec<(i16, i16)> {
+        // No f32::round without std, and `as` saturates negative values to 0
+        let num_frames = (seconds * (self.output_sample_rate() as f32) + 0.5) as usize;
+        let mut buffer = vec![(0, 0); num_frames];
+        self.render(&mut buffer);
+        buffer
+    }
+
+    pub fn render_f32(&mut self, buffer: &mut [(f32, f32)]) {
+        self.render_frames(buffer, 1, |out, (left, right)| {
+            out[0] = ((left as f32) / 32768.0, (right as f32) / 32768.0)
+        });
+    }
+
+    /// Renders into a flat L, R, L, R... buffer, as C APIs and WAV data expect. A trailing
+    /// odd sample is left untouched, so the length should be even.
+    pub fn render_interleaved(&mut self, buffer: &mut [i16]) {
+        let len = buffer.len() & !1;
+        self.render_frames(&mut buffer[..len], 2, |out, (left, right)| {
+            out[0] = left;
+            out[1] = right;
+        });
+    }
+
+    /// Renders like `render`, then fades the output out linearly over `fade_len` frames
+    /// starting `fade_start` frames into the tune. `elapsed_samples` is how many frames
+    /// were rendered before this call; everything past the end of the fade is silent.
+    pub fn render_with_fade(
+        &mut self,
+        buffer: &mut [(i16, i16)],
+        elapsed_samples: usize,
+        fade_start: usize,
+        fade_len: usize,
+    ) {
+        self.render(buffer);
+
+        for (i, (left, right)) in buffer.iter_mut().enumerate() {
+            let sample_index = elapsed_samples + i;
+            let f = if sample_index >= fade_start + fade_len {
+                0.0
+            } else if sample_index >= fade_start {
+                1.0 - ((sample_index - fade_start) as f32) / (fade_len as f32)
+            } else {
+                1.0
+            };
+            *left = ((*left as f32) * f) as i16;
+            *right = ((*right as f32) * f) as i16;
+        }
+    }
+
+    /// Advances emulation by `count` native-rate frames without handing any of them back,
+    /// leaving the APU in the same state `render` would have.
+    pub fn skip_samples(&mut self, count: usize) {
+        let mut remaining = count;
+        while remaining > 0 {
+            let num_samples = remaining.min(SAMPLES_BATCH_LEN);
+            self.fill_output_buffer(num_samples);
+            self.dsp.output_buffer.drain(..num_samples);
+            remaining -= num_samples;
+        }
+    }
+
+    /// Advances `samples` native-rate frames as quickly as possible, for seeking. Voices
+    /// skip interpolation and the echo FIR is bypassed along the way, so the echo buffer
+    /// only approximates a normal run; everything else ends up where `skip_samples` would
+    /// leave it.
+    pub fn render_fast(&mut self, samples: usize) {
+        let resampling_mode = self.dsp.resampling_mode();
+        self.dsp.set_resampling_mode(ResamplingMode::None);
+        self.dsp.set_echo_filter_bypassed(true);
+        self.skip_samples(samples);
+        self.dsp.set_echo_filter_bypassed(false);
+        self.dsp.set_resampling_mode(resampling_mode);
+    }
+
+    /// Renders `samples` frames at the output rate and throws them away, without allocating.
+    /// Timing a call to this against the wall clock gives emulated seconds per real second.
+    pub fn render_silent(&mut self, samples: usize) {
+        let mut remaining = samples;
+        while remaining > 0 {
+            let num_frames = remaining.min(SAMPLES_BATCH_LEN);
+            let num_samples = self.resampler.input_frames_needed(num_frames);
+            self.fill_output_buffer(num_samples);
+            for _ in 0..num_frames {
+                self.resampler.next(&mut self.dsp.output_buffer);
+            }
+            remaining -= num_frames;
+        }
+    }
+
+    /// Tries to find the period of the tune by running it for up to `max_seconds` and
+    /// looking for a repeat of everything `save_state` captures, bar the output position.
+    ///
+    /// State is hashed every 32 frames (1ms), so a loop is only found if it repeats on that
+    /// grid, and the returned period is a multiple of it. That state includes the DSP's
+    /// envelope rate counter, which wraps every 30720 frames (0.96s), so the period found is
+    /// always a multiple of that too. Each check hashes all 64kb of RAM and remembers 16
+    /// bytes, so memory use is around 1mb per minute searched. This advances the APU just
+    /// like `skip_samples` does.
+    pub fn detect_loop(&mut self, max_seconds: u32) -> Option<Duration> {
+        let mut seen_states = BTreeMap::new();
+        let max_samples = (max_seconds as usize) * SAMPLE_RATE;
+        let mut sample_pos = 0;
+        while sample_pos < max_samples {
+            if let Some(last_pos) = seen_states.insert(self.hash_state(), sample_pos) {
+                let period = (sample_pos - last_pos) as u64;
+                return Some(Duration::from_micros(
+                    period * 1_000_000 / (SAMPLE_RATE as u64),
+                ));
+            }
+
+            self.skip_samples(LOOP_DETECTION_BLOCK_LEN);
+            sample_pos += LOOP_DETECTION_BLOCK_LEN;
         }
+        None
+    }
+
+    fn hash_state(&self) -> u64 {
+        // This runs over all of RAM every time, so it needs to be cheap. The multiply only
+        //  carries differences upwards, so the shift folds them back down; without it, states
+        //  differing only in their top bits collide far too easily.
+        fn mix(hash: u64, word: u64) -> u64 {
+            let hash = (hash ^ word).wrapping_mul(0x517cc1b727220a95);
+            hash ^ (hash >> 32)
+        }
+
+        let mut writer = StateWriter::new();
+        self.save_emulation_state(&mut writer);
+        let state = writer.into_bytes();
+        let mut chunks = state.chunks_exact(8);
+        let mut hash = 0;
+        for chunk in &mut chunks {
+            hash = mix(hash, u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        for &byte in chunks.remainder() {
+            hash = mix(hash, byte as u64);
+        }
+        hash
+    }
+
+    /// Streams rendered frames one at a time. The APU never stops on its own, so this
+    /// iterator never returns `None`.
+    pub fn samples(&mut self) -> Samples<'_> {
+        Samples { apu: self }
+    }
+
+    /// Appends frames to `out` for as long as `should_continue` returns true. It's asked
+    /// before every frame, with the number of frames this call has appended so far.
+    pub fn render_until<F>(&mut self, out: &mut Vec<(i16, i16)>, mut should_continue: F)
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut num_frames = 0;
+        let mut samples = self.samples();
+        while should_continue(num_frames) {
+            out.push(samples.next().unwrap());
+            num_frames += 1;
+        }
+    }
+
+    // Each frame is handed to `write` along with the `frame_len` elements of `buffer` it
+    //  should fill.
+    fn render_frames<T, F>(&mut self, buffer: &mut [T], frame_len: usize, write: F)
+    where
+        F: Fn(&mut [T], (i16, i16)),
+    {
+        // Working in batches keeps the DSP's output buffer within its preallocated capacity
+        //  no matter how big the caller's buffer is.
+        for chunk in buffer.chunks_mut(SAMPLES_BATCH_LEN * frame_len) {
+            let num_samples = self.resampler.input_frames_needed(chunk.len() / frame_len);
+            self.fill_output_buffer(num_samples);
+
+            for out in chunk.chunks_exact_mut(frame_len) {
+                let sample = self.resampler.next(&mut self.dsp.output_buffer);
+                write(out, self.stereo_mode.apply(sample));
+            }
+        }
+    }
 
-        for (sample, out) in self.dsp.output_buffer.drain(..buffer.len()).zip(buffer) {
-            *out = sample;
+    fn fill_output_buffer(&mut self, num_samples: usize) {
+        while self.dsp.output_buffer.len() < num_samples {
+            let target_cycles = match self.timing_mode {
+                // Whatever the last run overshot by is either already in the buffer or
+                //  still pending in the DSP, so only ask for what's actually missing.
+                TimingMode::Fast => ((num_samples - self.dsp.output_buffer.len()) * 64)
+                    .saturating_sub(self.dsp.pending_cycles())
+                    .max(1),
+                TimingMode::Accurate => ACCURATE_TIMING_SLICE_CYCLES,
+            };
+            self.run(target_cycles);
         }
     }
 
+    /// Runs the SMP until at least `target_cycles` cycles have elapsed or it stops on a
+    /// breakpoint or watchpoint (see `Smp::break_reason`), then lets the DSP catch up.
+    /// Returns the number of cycles actually run.
+    pub fn run(&mut self, target_cycles: usize) -> usize {
+        let len = self.dsp.output_buffer.len();
+        // The SMP is taken out while it runs so it can be handed the rest of the APU.
+        //  Nothing it reaches through the APU looks at `self.smp` in the meantime.
+        let mut smp = mem::take(&mut self.smp);
+        let cycles = smp.run(self, target_cycles);
+        self.smp = smp;
+        self.flush_dsp();
+        self.samples_rendered += (self.dsp.output_buffer.len() - len) as u64;
+        cycles
+    }
+
+    /// Executes a single SMP instruction, lets the DSP catch up, and returns the number
+    /// of cycles the instruction took.
+    pub fn step(&mut self) -> usize {
+        let len = self.dsp.output_buffer.len();
+        let mut smp = mem::take(&mut self.smp);
+        let cycles = smp.step(self);
+        self.smp = smp;
+        self.flush_dsp();
+        self.samples_rendered += (self.dsp.output_buffer.len() - len) as u64;
+        cycles
+    }
+
+    /// Generates one stereo frame straight from the DSP and returns it, without running
+    /// the SMP or touching the output buffer. Register writes made between steps take
+    /// effect on the next one, which makes envelope and echo behaviour easy to follow
+    /// frame by frame.
+    pub fn step_dsp(&mut self) -> (i16, i16) {
+        let mut memory = DspMemory {
+            ram: &mut self.ram,
+            echo_sandbox: self.echo_sandbox.as_deref_mut(),
+            is_write_enabled: self.is_ram_write_enabled,
+        };
+        self.dsp.step(&mut memory)
+    }
+
+    fn flush_dsp(&mut self) {
+        let mut memory = DspMemory {
+            ram: &mut self.ram,
+            echo_sandbox: self.echo_sandbox.as_deref_mut(),
+            is_write_enabled: self.is_ram_write_enabled,
+        };
+        self.dsp.flush(&mut memory);
+    }
+
+    /// How many native-rate samples have been played out since boot or the last `reset`,
+    /// whether by rendering or skipping. Samples the DSP has generated but `render`
+    /// hasn't handed out yet don't count.
+    pub fn samples_rendered(&self) -> u64 {
+        self.samples_rendered
+            .saturating_sub(self.dsp.output_buffer.len() as u64)
+    }
+
+    /// `samples_rendered` as emulated time, for seek bars and the like.
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.samples_rendered() * 1_000_000 / (SAMPLE_RATE as u64))
+    }
+
     pub fn cpu_cycles_callback(&mut self, num_cycles: usize) {
         self.dsp.cycles_callback(num_cycles);
-        for timer in self.timers.iter_mut() {
-            timer.cpu_cycles_callback(num_cycles);
+        if self.are_timers_enabled {
+            for timer in self.timers.iter_mut() {
+                timer.cpu_cycles_callback(num_cycles);
+            }
         }
     }
 
@@ -94,7 +688,12 @@ impl<'apu> Apu<'apu> {
             0xf0 | 0xf1 => 0,
 
             0xf2 => self.dsp_reg_address,
-            0xf3 => self.dsp.get_register(self.dsp_reg_address),
+            0xf3 => {
+                self.flush_dsp();
+                self.dsp.get_register(self.dsp_reg_address)
+            }
+
+            0xf4..=0xf7 => self.cpu_ports[(address - 0xf4) as usize],
 
             0xfa..=0xfc => 0,
 
@@ -110,6 +709,31 @@ impl<'apu> Apu<'apu> {
         }
     }
 
+    /// Reads like `read_u8` would, but without side effects: the timer counters aren't
+    /// cleared and the DSP isn't caught up first, so ENVX/OUTX may lag slightly.
+    pub fn peek_u8(&self, address: u16) -> u8 {
+        match address {
+            0xf0 | 0xf1 => 0,
+
+            0xf2 => self.dsp_reg_address,
+            0xf3 => self.dsp.get_state()[(self.dsp_reg_address & 0x7f) as usize],
+
+            0xf4..=0xf7 => self.cpu_ports[(address - 0xf4) as usize],
+
+            0xfa..=0xfc => 0,
+
+            0xfd => self.timers[0].peek_counter(),
+            0xfe => self.timers[1].peek_counter(),
+            0xff => self.timers[2].peek_counter(),
+
+            addr if addr >= 0xffc0 && self.is_ipl_rom_enabled => {
+                self.ipl_rom[(addr - 0xffc0) as usize]
+            }
+
+            _ => self.ram[address as usize],
+        }
+    }
+
     pub fn write_u8(&mut self, address: u16, value: u8) {
         match address {
             0xf0 => {
@@ -122,9 +746,14 @@ impl<'apu> Apu<'apu> {
                 self.dsp_reg_address = value;
             }
             0xf3 => {
+                self.flush_dsp();
                 self.dsp.set_register(self.dsp_reg_address, value);
             }
 
+            0xf4..=0xf7 => {
+                self.smp_ports[(address - 0xf4) as usize] = value;
+            }
+
             0xfa => {
                 self.timers[0].set_target(value);
             }
@@ -137,37 +766,1119 @@ impl<'apu> Apu<'apu> {
 
             0xfd..=0xff => (), // Do nothing
 
-            _ => self.ram[address as usize] = value,
+            _ => {
+                if self.is_ram_write_enabled {
+                    self.ram[address as usize] = value;
+                }
+            }
         }
     }
 
+    /// Writes one of the four ports the SNES CPU uses to talk to the SMP, as the CPU
+    /// would. The SMP sees it at $f4-$f7 until the CPU writes again or the SMP clears it
+    /// through CONTROL. Like the CPU's $2140-$217f, port numbers past 3 wrap round.
+    pub fn write_port(&mut self, port: usize, value: u8) {
+        self.cpu_ports[port & 3] = value;
+    }
+
+    /// Reads one of the four ports as the SNES CPU would, returning whatever the SMP last
+    /// wrote to $f4-$f7. The two directions are separate latches, so this never sees
+    /// values from `write_port`. Port numbers past 3 wrap round.
+    pub fn read_port(&self, port: usize) -> u8 {
+        self.smp_ports[port & 3]
+    }
+
+    pub fn set_ipl_rom(&mut self, rom: [u8; IPL_ROM_LEN]) {
+        self.ipl_rom = rom;
+    }
+
+    /// Reads ARAM directly, bypassing the I/O ports and the IPL ROM overlay.
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+
+    /// Writes ARAM directly. Unlike `write_u8`, this never touches the DSP or timers.
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        self.ram[address as usize] = value;
+    }
+
+    pub fn ram_slice(&self) -> &[u8; RAM_LEN] {
+        &self.ram
+    }
+
+    /// With the sandbox on, the DSP's echo reads and writes go to a private copy of RAM
+    /// taken when it's enabled, so a misplaced echo buffer can't trample code or data.
+    /// Off by default, since real hardware has no such protection.
+    pub fn set_echo_sandbox(&mut self, enabled: bool) {
+        if !enabled {
+            self.echo_sandbox = None;
+        } else if self.echo_sandbox.is_none() {
+            self.echo_sandbox = Some(self.ram.clone());
+        }
+    }
+
+    pub fn is_echo_sandboxed(&self) -> bool {
+        self.echo_sandbox.is_some()
+    }
+
     pub fn clear_echo_buffer(&mut self) {
-        let length = self.dsp.calculate_echo_length();
-        let mut end_addr = self.dsp.get_echo_start_address() as i32 + length;
-        if end_addr > RAM_LEN as i32 {
-            end_addr = RAM_LEN as i32;
+        // Like the DSP's own accesses, the buffer wraps around the end of RAM
+        let length = self.dsp.echo_buffer_size_bytes();
+        let start_addr = self.dsp.get_echo_start_address();
+        let echo_ram = match self.echo_sandbox {
+            Some(ref mut sandbox) => sandbox,
+            None => &mut self.ram,
+        };
+        for i in 0..length {
+            echo_ram[start_addr.wrapping_add(i as u16) as usize] = 0x00;
         }
-        for i in (self.dsp.get_echo_start_address() as i32)..end_addr {
-            self.ram[i as usize] = 0xff;
+    }
+
+    /// Makes `Some(voice)` the only audible voice, or lets every voice through again with
+    /// `None`. This is tracked separately from muting, so voices muted beforehand stay
+    /// muted once the solo is lifted.
+    pub fn solo_voice(&mut self, voice: Option<usize>) {
+        for (i, v) in self.dsp.voices.iter_mut().enumerate() {
+            v.is_solod = voice == Some(i);
         }
     }
 
-    fn set_test_reg(&self, _value: u8) {
-        unimplemented!("Test reg not yet implemented");
+    /// Timers 0 and 1 tick at 8kHz and timer 2 at 64kHz.
+    pub fn timer(&self, index: usize) -> &Timer {
+        &self.timers[index]
+    }
+
+    pub fn is_ipl_rom_enabled(&self) -> bool {
+        self.is_ipl_rom_enabled
+    }
+
+    /// Maps the IPL ROM over $ffc0-$ffff or not, just like bit 7 of CONTROL does.
+    pub fn set_ipl_rom_enabled(&mut self, enabled: bool) {
+        self.is_ipl_rom_enabled = enabled;
+    }
+
+    pub fn test_reg(&self) -> u8 {
+        self.test_reg
+    }
+
+    /// Whether TEST currently lets the timers tick. When it doesn't, all three hold their
+    /// counters regardless of how many cycles go by.
+    pub fn are_timers_enabled(&self) -> bool {
+        self.are_timers_enabled
+    }
+
+    fn set_test_reg(&mut self, value: u8) {
+        // Bits 4-7 select RAM/IO wait states, which only matter for cycle timing
+        //  on real hardware. $f0 itself reads back as 0, so the whole value is only kept
+        //  for `test_reg` and SPC export.
+        self.test_reg = value;
+        // Bit 1 gates writes from both the SMP and the DSP's echo buffer
+        self.is_ram_write_enabled = (value & 0x02) != 0;
+        // Timers only tick with bit 0 clear and bit 3 set (the power-on value is 0x0a)
+        self.are_timers_enabled = (value & 0x01) == 0 && (value & 0x08) != 0;
+    }
+
+    #[cfg(feature = "std")]
+    fn get_control_reg(&self) -> u8 {
+        (if self.is_ipl_rom_enabled { 0x80 } else { 0 })
+            | (if self.timers[2].is_running() { 0x04 } else { 0 })
+            | (if self.timers[1].is_running() { 0x02 } else { 0 })
+            | (if self.timers[0].is_running() { 0x01 } else { 0 })
     }
 
     fn set_control_reg(&mut self, value: u8) {
         self.is_ipl_rom_enabled = (value & 0x80) != 0;
         if (value & 0x20) != 0 {
-            self.write_u8(0xf6, 0x00);
-            self.write_u8(0xf7, 0x00);
+            self.cpu_ports[2] = 0;
+            self.cpu_ports[3] = 0;
         }
         if (value & 0x10) != 0 {
-            self.write_u8(0xf4, 0x00);
-            self.write_u8(0xf5, 0x00);
+            self.cpu_ports[0] = 0;
+            self.cpu_ports[1] = 0;
         }
         self.timers[0].set_start_stop_bit((value & 0x01) != 0);
         self.timers[1].set_start_stop_bit((value & 0x02) != 0);
         self.timers[2].set_start_stop_bit((value & 0x04) != 0);
     }
 }
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+/// Collects settings for a new `Apu`. Anything left alone keeps the same default
+/// `Apu::new` uses.
+pub struct ApuBuilder {
+    resampling_mode: ResamplingMode,
+    stereo_mode: StereoMode,
+    timing_mode: TimingMode,
+    output_sample_rate: u32,
+    master_gain: f32,
+    ipl_rom: Option<[u8; IPL_ROM_LEN]>,
+    apply_channel_disables: bool,
+}
+
+impl ApuBuilder {
+    pub fn new() -> ApuBuilder {
+        ApuBuilder {
+            resampling_mode: ResamplingMode::Gaussian,
+            stereo_mode: StereoMode::Full,
+            timing_mode: TimingMode::Fast,
+            output_sample_rate: SAMPLE_RATE as u32,
+            master_gain: 1.0,
+            ipl_rom: None,
+            apply_channel_disables: false,
+        }
+    }
+
+    pub fn resampling_mode(mut self, mode: ResamplingMode) -> ApuBuilder {
+        self.resampling_mode = mode;
+        self
+    }
+
+    pub fn stereo_mode(mut self, mode: StereoMode) -> ApuBuilder {
+        self.stereo_mode = mode;
+        self
+    }
+
+    pub fn timing_mode(mut self, mode: TimingMode) -> ApuBuilder {
+        self.timing_mode = mode;
+        self
+    }
+
+    /// Clamped the same way `Apu::set_output_sample_rate` does.
+    pub fn output_sample_rate(mut self, hz: u32) -> ApuBuilder {
+        self.output_sample_rate = hz;
+        self
+    }
+
+    pub fn master_gain(mut self, gain: f32) -> ApuBuilder {
+        self.master_gain = gain;
+        self
+    }
+
+    /// Replaces the IPL ROM. When building from an SPC, this takes precedence over the
+    /// ROM stored in the file.
+    pub fn ipl_rom(mut self, rom: [u8; IPL_ROM_LEN]) -> ApuBuilder {
+        self.ipl_rom = Some(rom);
+        self
+    }
+
+    /// Mutes the voices the SPC's ID666 tag lists as disabled by default when building
+    /// from an SPC. Off by default, so every voice is audible.
+    pub fn apply_channel_disables(mut self, apply: bool) -> ApuBuilder {
+        self.apply_channel_disables = apply;
+        self
+    }
+
+    pub fn build(&self) -> Apu {
+        let mut ret = Apu::new();
+        self.configure(&mut ret);
+        ret
+    }
+
+    #[cfg(feature = "std")]
+    pub fn build_from_spc(&self, spc: &Spc) -> Apu {
+        let mut ret = Apu::from_spc(spc);
+        self.configure(&mut ret);
+        if self.apply_channel_disables {
+            if let Some(ref id666_tag) = spc.id666_tag {
+                for voice in 0..ret.dsp.voices.len() {
+                    if (id666_tag.default_channel_disables & (1 << voice)) != 0 {
+                        ret.dsp.set_voice_muted(voice, true);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn configure(&self, apu: &mut Apu) {
+        apu.dsp.set_resampling_mode(self.resampling_mode);
+        apu.dsp.set_master_gain(self.master_gain);
+        apu.set_stereo_mode(self.stereo_mode);
+        apu.set_timing_mode(self.timing_mode);
+        apu.set_output_sample_rate(self.output_sample_rate);
+        if let Some(rom) = self.ipl_rom {
+            apu.set_ipl_rom(rom);
+        }
+    }
+}
+
+impl Default for ApuBuilder {
+    fn default() -> ApuBuilder {
+        ApuBuilder::new()
+    }
+}
+
+pub struct Samples<'a> {
+    apu: &'a mut Apu,
+}
+
+impl Iterator for Samples<'_> {
+    type Item = (i16, i16);
+
+    fn next(&mut self) -> Option<(i16, i16)> {
+        if self.apu.dsp.output_buffer.len() < self.apu.resampler.input_frames_needed(1) {
+            self.apu.fill_output_buffer(SAMPLES_BATCH_LEN);
+        }
+        let sample = self.apu.resampler.next(&mut self.apu.dsp.output_buffer);
+        Some(self.apu.stereo_mode.apply(sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dsp::dsp::tests::{is_silent, tone_apu};
+    use super::super::dsp::dsp::OutputTap;
+    use super::*;
+
+    #[test]
+    fn runs_after_being_moved() {
+        let apus = vec![Apu::new(), Apu::new()];
+        let mut apu = apus.into_iter().nth(1).unwrap();
+
+        // The IPL ROM clears the zero page before waiting on the ports
+        apu.write_ram(0x0042, 0x55);
+        assert!(apu.run(10000) >= 10000);
+        assert_eq!(apu.read_ram(0x0042), 0);
+        assert!(apu.smp.reg_pc >= 0xffc0);
+
+        let mut buffer = [(1, 1); 64];
+        apu.render(&mut buffer);
+        assert_eq!(buffer, [(0, 0); 64]);
+    }
+
+    #[test]
+    fn can_be_sent_between_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Apu>();
+    }
+
+    #[test]
+    fn resamples_to_the_output_rate() {
+        let mut apu = Apu::new();
+        apu.set_output_sample_rate(44100);
+        assert_eq!(apu.render_seconds(1.0).len(), 44100);
+        // A second at 44.1kHz takes a second's worth of DSP output, plus the couple of
+        //  frames the resampler reads ahead
+        let consumed = apu.samples_rendered();
+        assert!((32000..=32002).contains(&consumed), "{}", consumed);
+        assert_eq!(apu.render_seconds(0.5).len(), 22050);
+    }
+
+    #[test]
+    fn builder_applies_settings() {
+        let mut rom = DEFAULT_IPL_ROM;
+        rom[0] = 0x00;
+        let apu = ApuBuilder::default()
+            .resampling_mode(ResamplingMode::Linear)
+            .stereo_mode(StereoMode::Mono)
+            .timing_mode(TimingMode::Accurate)
+            .output_sample_rate(48000)
+            .master_gain(0.5)
+            .ipl_rom(rom)
+            .build();
+
+        assert!(matches!(apu.dsp.resampling_mode(), ResamplingMode::Linear));
+        assert_eq!(apu.stereo_mode(), StereoMode::Mono);
+        assert_eq!(apu.timing_mode(), TimingMode::Accurate);
+        assert_eq!(apu.output_sample_rate(), 48000);
+        assert_eq!(apu.dsp.master_gain(), 0.5);
+        assert_eq!(apu.peek_u8(0xffc0), 0x00);
+
+        let defaults = ApuBuilder::default().build();
+        assert_eq!(defaults.output_sample_rate(), SAMPLE_RATE as u32);
+        assert_eq!(defaults.peek_u8(0xffc0), DEFAULT_IPL_ROM[0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn builder_applies_channel_disables() {
+        use crate::spc::spc::{Emulator, Id666Tag};
+
+        let mut spc = Apu::new().to_spc();
+        spc.id666_tag = Some(Id666Tag {
+            song_title: String::new(),
+            game_title: String::new(),
+            dumper_name: String::new(),
+            comments: String::new(),
+            date_dumped: String::new(),
+            seconds_to_play_before_fading_out: 0,
+            fade_out_length: 0,
+            artist_name: String::new(),
+            default_channel_disables: 0b1000_0101,
+            dumping_emulator: Emulator::Unknown,
+        });
+
+        let apu = ApuBuilder::new().build_from_spc(&spc);
+        assert!((0..8).all(|voice| !apu.dsp.is_voice_muted(voice)));
+
+        let apu = ApuBuilder::new()
+            .apply_channel_disables(true)
+            .build_from_spc(&spc);
+        let muted: Vec<usize> = (0..8).filter(|&v| apu.dsp.is_voice_muted(v)).collect();
+        assert_eq!(muted, [0, 2, 7]);
+    }
+
+    // Runs the SMP until port 0 reads back `value`, like the CPU spinning on it
+    fn wait_for_port(apu: &mut Apu, value: u8) {
+        for _ in 0..1000 {
+            if apu.read_port(0) == value {
+                return;
+            }
+            apu.run(64);
+        }
+        panic!("port 0 never read {:#04x}", value);
+    }
+
+    #[test]
+    fn uploads_through_the_ipl_rom() {
+        let mut apu = Apu::new();
+        wait_for_port(&mut apu, 0xaa);
+        assert_eq!(apu.read_port(1), 0xbb);
+
+        // Start a transfer to $0300
+        apu.write_port(2, 0x00);
+        apu.write_port(3, 0x03);
+        apu.write_port(1, 0x01);
+        apu.write_port(0, 0xcc);
+        // Each side only sees what the other wrote, not its own writes
+        assert_eq!(apu.read_port(0), 0xaa);
+        assert_eq!(apu.peek_u8(0xf4), 0xcc);
+        wait_for_port(&mut apu, 0xcc);
+
+        for (index, &value) in [0x42, 0x99].iter().enumerate() {
+            apu.write_port(1, value);
+            apu.write_port(0, index as u8);
+            wait_for_port(&mut apu, index as u8);
+        }
+        assert_eq!(apu.read_ram(0x0300), 0x42);
+        assert_eq!(apu.read_ram(0x0301), 0x99);
+        assert_eq!(apu.peek_u8(0xf5), 0x99);
+        assert_eq!(apu.read_port(1), 0xbb);
+
+        // Port numbers wrap like the CPU's mirrors of them do
+        apu.write_port(6, 0x12);
+        assert_eq!(apu.peek_u8(0xf6), 0x12);
+        assert_eq!(apu.read_port(5), apu.read_port(1));
+    }
+
+    #[test]
+    fn ram_accessors_skip_the_io_registers() {
+        let mut apu = Apu::new();
+        apu.write_u8(0xf2, 0x0c);
+        apu.write_ram(0xf3, 0x55);
+        apu.write_ram(0xfa, 0x10);
+        apu.write_ram(0xffc0, 0xaa);
+
+        assert_eq!(apu.read_ram(0xf3), 0x55);
+        assert_eq!(apu.read_ram(0xffc0), 0xaa);
+        assert_eq!(apu.ram_slice()[0xfa], 0x10);
+        // None of that reached the DSP, timers or the IPL ROM
+        assert_eq!(apu.dsp.get_register(0x0c), 0);
+        assert_eq!(apu.timer(0).target(), None);
+        assert_eq!(apu.read_u8(0xffc0), 0xcd);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn to_spc_round_trips_through_from_spc() {
+        let mut spc = Apu::new().to_spc();
+        spc.pc = 0x1234;
+        spc.a = 0x56;
+        spc.x = 0x78;
+        spc.y = 0x9a;
+        spc.psw = 0xb5;
+        spc.sp = 0xcd;
+        for (i, byte) in spc.ram.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(31) ^ (i >> 8) as u8;
+        }
+        // TEST isn't restored from a snapshot, and CONTROL's port clearing bits read
+        //  back as 0
+        spc.ram[0xf0] = DEFAULT_TEST_REG;
+        spc.ram[0xf1] = 0x83;
+        for (i, reg) in spc.regs.iter_mut().enumerate() {
+            *reg = match i & 0x0f {
+                0x08 | 0x09 => 0,
+                _ => (i as u8).wrapping_mul(13),
+            };
+        }
+        spc.ipl_rom[0] = 0xff;
+
+        let mut apu = Apu::from_spc(&spc);
+        apu.run(0);
+        let saved = apu.to_spc();
+        assert_eq!(
+            (saved.pc, saved.a, saved.x, saved.y, saved.psw, saved.sp),
+            (spc.pc, spc.a, spc.x, spc.y, spc.psw, spc.sp)
+        );
+        assert!(saved.ram[..] == spc.ram[..]);
+        assert_eq!(saved.regs, spc.regs);
+        assert_eq!(saved.ipl_rom, spc.ipl_rom);
+    }
+
+    #[test]
+    fn reads_a_custom_ipl_rom() {
+        let mut rom = [0; IPL_ROM_LEN];
+        for (i, byte) in rom.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        let mut apu = Apu::new();
+        apu.write_ram(0xffc0, 0xee);
+        apu.set_ipl_rom(rom);
+        for (i, &byte) in rom.iter().enumerate() {
+            assert_eq!(apu.read_u8(0xffc0 + i as u16), byte);
+        }
+        // The RAM underneath is still there once the ROM is switched out
+        apu.write_u8(0xf1, 0x00);
+        assert_eq!(apu.read_u8(0xffc0), 0xee);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_spc_copies_the_ipl_rom() {
+        let mut spc = Apu::new().to_spc();
+        spc.ipl_rom = [0x42; IPL_ROM_LEN];
+        spc.ram[0xf1] = 0x80;
+        let apu = Apu::from_spc(&spc);
+        assert!((0xffc0..=0xffff).all(|addr| apu.peek_u8(addr) == 0x42));
+        assert_eq!(apu.to_spc().ipl_rom, spc.ipl_rom);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn loads_and_boots_an_spc() {
+        let path = "test/ferris-nu.spc";
+        let apu = Apu::load(path).unwrap();
+        let expected = Apu::from_spc(&Spc::load(path).unwrap());
+        assert!(apu.save_state() == expected.save_state());
+
+        assert!(Apu::load("test/does-not-exist.spc").is_err());
+    }
+
+    #[test]
+    fn test_reg_can_stop_the_timers() {
+        let mut apu = Apu::new();
+        apu.write_u8(0xfc, 1);
+        apu.write_u8(0xf1, 0x04);
+
+        // Bit 0 set halts the timers, and so does bit 3 clear
+        for value in [0x0b, 0x02] {
+            apu.write_u8(0xf0, value);
+            assert!(!apu.are_timers_enabled());
+            apu.cpu_cycles_callback(100);
+            assert_eq!(apu.read_u8(0xff), 0);
+        }
+
+        apu.write_u8(0xf0, 0x0a);
+        assert!(apu.are_timers_enabled());
+        apu.cpu_cycles_callback(100);
+        assert_eq!(apu.read_u8(0xff), 3);
+
+        assert_eq!(apu.test_reg(), 0x0a);
+        assert_eq!(apu.read_u8(0xf0), 0);
+    }
+
+    #[test]
+    fn test_reg_pauses_all_the_timers() {
+        // Spin on BRA with all three timers counting every tick
+        let mut apu = run_program(Apu::new(), &[0x2f, 0xfe]);
+        for address in 0xfa..0xfd {
+            apu.write_u8(address, 1);
+        }
+        apu.write_u8(0xf1, 0x07);
+
+        apu.write_u8(0xf0, 0x0b);
+        apu.run(10000);
+        for timer in apu.timers.iter_mut() {
+            assert_eq!(timer.read_counter(), 0);
+        }
+
+        // Resuming sets them counting again, the third one eight times as fast
+        apu.write_u8(0xf0, 0x0a);
+        apu.run(768);
+        let counters = [0xfd, 0xfe, 0xff].map(|address| apu.read_u8(address));
+        assert_eq!(counters, [3, 3, 24 & 0x0f]);
+    }
+
+    #[test]
+    fn peeking_leaves_the_io_registers_alone() {
+        let mut apu = Apu::new();
+        apu.write_u8(0xfa, 1);
+        apu.write_u8(0xf1, 0x01);
+        apu.cpu_cycles_callback(256 * 5);
+
+        assert_eq!(apu.peek_u8(0xfd), 5);
+        assert_eq!(apu.peek_u8(0xfd), 5);
+        assert_eq!(apu.read_u8(0xfd), 5);
+        assert_eq!(apu.peek_u8(0xfd), 0);
+
+        apu.write_u8(0xf2, 0x0c);
+        apu.write_u8(0xf3, 0x45);
+        assert_eq!(apu.peek_u8(0xf3), 0x45);
+        assert_eq!(apu.peek_u8(0xf2), 0x0c);
+        assert_eq!(apu.peek_u8(0xffc0), apu.read_u8(0xffc0));
+    }
+
+    #[test]
+    fn ipl_rom_overlay_can_be_switched_off() {
+        let mut apu = Apu::new();
+        assert!(apu.is_ipl_rom_enabled());
+        // MOV A,#$42, hidden under the ROM's MOV X,#$EF
+        apu.write_ram(0xffc0, 0xe8);
+        apu.write_ram(0xffc1, 0x42);
+        assert_eq!(apu.read_u8(0xffc0), 0xcd);
+
+        apu.set_ipl_rom_enabled(false);
+        assert!(!apu.is_ipl_rom_enabled());
+        assert_eq!(apu.read_u8(0xffc0), 0xe8);
+        assert_eq!(apu.peek_u8(0xffc1), 0x42);
+        apu.step();
+        assert_eq!(apu.smp.reg_a, 0x42);
+
+        apu.set_ipl_rom_enabled(true);
+        assert_eq!(apu.read_u8(0xffc0), 0xcd);
+    }
+
+    #[test]
+    fn timers_report_their_settings() {
+        let mut apu = Apu::new();
+        let resolutions = [0, 1, 2].map(|index| apu.timer(index).resolution());
+        assert_eq!(resolutions, [256, 256, 32]);
+        assert!((0..3).all(|index| !apu.timer(index).is_running()));
+
+        apu.write_u8(0xfa, 0x10);
+        apu.write_u8(0xfb, 0x00);
+        apu.write_u8(0xfc, 0xff);
+        apu.write_u8(0xf1, 0x05);
+        let targets = [0, 1, 2].map(|index| apu.timer(index).target());
+        // A target of 0 counts all the way round, 256 ticks
+        assert_eq!(targets, [Some(0x10), None, Some(0xff)]);
+        let running = [0, 1, 2].map(|index| apu.timer(index).is_running());
+        assert_eq!(running, [true, false, true]);
+    }
+
+    #[test]
+    fn soloing_layers_over_the_mutes() {
+        let [mut soloed, mut expected] = [(); 2].map(|_| {
+            let mut apu = tone_apu();
+            apu.dsp.key_on(0x07);
+            apu.dsp.set_voice_muted(1, true);
+            apu
+        });
+        soloed.solo_voice(Some(2));
+        expected.dsp.set_voice_muted(0, true);
+        let mut heard = false;
+        for _ in 0..500 {
+            let frame = soloed.step_dsp();
+            assert_eq!(frame, expected.step_dsp());
+            heard |= frame != (0, 0);
+        }
+        assert!(heard);
+
+        // Voice 1 stays muted once the solo's lifted
+        soloed.solo_voice(None);
+        expected.dsp.set_voice_muted(0, false);
+        assert!(soloed.dsp.is_voice_muted(1));
+        for _ in 0..500 {
+            assert_eq!(soloed.step_dsp(), expected.step_dsp());
+        }
+    }
+
+    #[test]
+    fn echo_writes_land_in_ram() {
+        let mut apu = tone_apu();
+        for address in 0x8ff0..0x9810 {
+            apu.write_ram(address, 0xaa);
+        }
+        // A 2kb echo buffer at $9000, fed by voice 0
+        for (register, value) in [(0x6d, 0x90), (0x7d, 0x01), (0x4d, 0x01), (0x6c, 0x00)] {
+            apu.write_u8(0xf2, register);
+            apu.write_u8(0xf3, value);
+        }
+        apu.dsp.key_on(0x01);
+        for _ in 0..8 {
+            apu.run(64 * 64);
+        }
+
+        assert!((0x9000..0x9800).all(|address| apu.read_ram(address) != 0xaa));
+        assert!((0x8ff0..0x9000).all(|address| apu.read_ram(address) == 0xaa));
+        assert!((0x9800..0x9810).all(|address| apu.read_ram(address) == 0xaa));
+    }
+
+    #[test]
+    fn renders_seconds_at_the_output_rate() {
+        let mut apu = playing_apu();
+        let mut expected = vec![(0, 0); 16000];
+        playing_apu().render(&mut expected);
+        assert!(apu.render_seconds(0.5) == expected);
+
+        // Rounded to the nearest frame, and never negative
+        assert_eq!(apu.render_seconds(1.4 / 32000.0).len(), 1);
+        assert_eq!(apu.render_seconds(1.6 / 32000.0).len(), 2);
+        assert_eq!(apu.render_seconds(-1.0).len(), 0);
+
+        apu.set_output_sample_rate(44100);
+        assert_eq!(apu.render_seconds(2.0).len(), 88200);
+    }
+
+    #[test]
+    fn echo_sandbox_protects_ram() {
+        let [mut sandboxed, mut expected] = [(); 2].map(|_| {
+            let mut apu = tone_apu();
+            for address in 0x9000..0x9800 {
+                apu.write_ram(address, 0xaa);
+            }
+            // A 2kb echo buffer at $9000, fed by voice 0 with some feedback
+            for (register, value) in [(0x6d, 0x90), (0x7d, 0x01), (0x4d, 0x01), (0x0d, 0x40)] {
+                apu.dsp.set_register(register, value);
+            }
+            apu.dsp.set_register(0x6c, 0x00);
+            apu.dsp.set_register(0x2c, 0x7f);
+            apu.dsp.set_output_tap(OutputTap::EchoOnly);
+            apu.dsp.key_on(0x01);
+            apu
+        });
+        sandboxed.set_echo_sandbox(true);
+        assert!(sandboxed.is_echo_sandboxed());
+
+        // The echo sounds just the same...
+        let mut heard = false;
+        for _ in 0..2000 {
+            let frame = sandboxed.step_dsp();
+            assert_eq!(frame, expected.step_dsp());
+            heard |= frame != (0, 0);
+        }
+        assert!(heard);
+        assert!((0x9000..0x9800).any(|address| expected.read_ram(address) != 0xaa));
+        // ...without touching the RAM underneath
+        assert!((0x9000..0x9800).all(|address| sandboxed.read_ram(address) == 0xaa));
+    }
+
+    #[test]
+    fn test_reg_can_disable_ram_writes() {
+        let mut apu = Apu::new();
+        for address in 0x8000..0x8004 {
+            apu.write_ram(address, 0x55);
+        }
+        // Echo writes on, with a 4-byte buffer at $8000 fed nothing but silence
+        for (register, value) in [(0x6c, 0x00), (0x6d, 0x80), (0x7d, 0x00), (0x0d, 0x00)] {
+            apu.write_u8(0xf2, register);
+            apu.write_u8(0xf3, value);
+        }
+
+        apu.write_u8(0xf0, 0x08);
+        apu.write_u8(0x0200, 0x12);
+        assert_eq!(apu.read_ram(0x0200), 0);
+        apu.step_dsp();
+        apu.step_dsp();
+        assert_eq!(apu.read_ram(0x8000), 0x55);
+        assert_eq!(apu.read_ram(0x8003), 0x55);
+
+        apu.write_u8(0xf0, 0x0a);
+        apu.write_u8(0x0200, 0x12);
+        assert_eq!(apu.read_ram(0x0200), 0x12);
+        apu.step_dsp();
+        apu.step_dsp();
+        assert_eq!(apu.read_ram(0x8000), 0);
+        assert_eq!(apu.read_ram(0x8003), 0);
+    }
+
+    // Echo at full volume through a pass-through FIR, reading a 2KB buffer at $8000 that's
+    //  been filled with junk. Echo writes are off, so the junk stays put.
+    fn junk_echo_apu() -> Apu {
+        let mut apu = Apu::new();
+        for address in 0x8000..0x8800 {
+            apu.write_ram(address, 0x40);
+        }
+        for (address, value) in [(0x2c, 0x7f), (0x3c, 0x7f), (0x6c, 0x20), (0x6d, 0x80)] {
+            apu.dsp.set_register(address, value);
+        }
+        apu.dsp.set_echo_delay(1);
+        apu.dsp.set_register(0x0f, 0x7f);
+        for tap in 1..8 {
+            apu.dsp.set_register((tap << 4) | 0x0f, 0);
+        }
+        apu
+    }
+
+    #[test]
+    fn cleared_echo_buffer_is_silent() {
+        assert!(!is_silent(&mut junk_echo_apu(), 64));
+
+        let mut apu = junk_echo_apu();
+        apu.clear_echo_buffer();
+        assert!(is_silent(&mut apu, 64));
+    }
+
+    // Fills RAM with junk, clears an echo buffer at `esa` << 8 of EDL `edl`, and returns
+    //  which bytes were cleared
+    fn cleared_by(esa: u8, edl: u8) -> Vec<usize> {
+        let mut apu = Apu::new();
+        for address in 0..RAM_LEN {
+            apu.write_ram(address as u16, 0x55);
+        }
+        apu.dsp.set_register(0x6d, esa);
+        apu.dsp.set_echo_delay(edl);
+        apu.clear_echo_buffer();
+        (0..RAM_LEN)
+            .filter(|&address| apu.ram[address] == 0)
+            .collect()
+    }
+
+    #[test]
+    fn clears_the_echo_buffer() {
+        // EDL = 0 still leaves the hardware using a single 4-byte frame
+        assert_eq!(cleared_by(0x80, 0), (0x8000..0x8004).collect::<Vec<_>>());
+        assert_eq!(cleared_by(0x80, 2), (0x8000..0x9000).collect::<Vec<_>>());
+        // A buffer running off the end of RAM wraps round to the start
+        let mut expected: Vec<usize> = (0..0x0700).collect();
+        expected.extend(0xff00..0x10000);
+        assert_eq!(cleared_by(0xff, 1), expected);
+    }
+
+    fn playing_apu() -> Apu {
+        let mut apu = tone_apu();
+        apu.dsp.key_on(0xff);
+        apu
+    }
+
+    #[test]
+    fn reset_returns_to_power_on() {
+        let mut apu = playing_apu();
+        apu.render_seconds(0.1);
+        apu.reset();
+        assert!(apu.save_state() == Apu::new().save_state());
+        assert_eq!(apu.smp.registers(), Apu::new().smp.registers());
+        assert_eq!(apu.dsp.register_file(), Apu::new().dsp.register_file());
+
+        let mut expected = vec![(0, 0); 1000];
+        Apu::new().render(&mut expected);
+        let mut rendered = vec![(0, 0); 1000];
+        apu.render(&mut rendered);
+        assert!(rendered == expected);
+    }
+
+    #[test]
+    fn small_renders_match_one_big_one() {
+        // Enough frames for the DSP's buffer to wrap round
+        let mut expected = vec![(0, 0); 110000];
+        playing_apu().render(&mut expected);
+        assert!(expected.iter().any(|&frame| frame != (0, 0)));
+
+        let mut apu = playing_apu();
+        let capacity = apu.dsp.output_buffer.capacity();
+        let mut rendered = Vec::new();
+        for _ in 0..10000 {
+            let mut buffer = [(0, 0); 11];
+            apu.render(&mut buffer);
+            rendered.extend_from_slice(&buffer);
+        }
+        assert!(rendered == expected);
+        // ...and did so without growing
+        assert_eq!(apu.dsp.output_buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn saved_state_carries_on_where_it_left_off() {
+        let mut control = playing_apu();
+        control.render_seconds(0.1);
+        let mut expected = vec![(0, 0); 5000];
+        control.render(&mut expected);
+
+        let mut apu = playing_apu();
+        apu.render_seconds(0.1);
+        let mut loaded = Apu::new();
+        loaded.load_state(&apu.save_state()).unwrap();
+        let mut rendered = vec![(0, 0); 5000];
+        loaded.render(&mut rendered);
+
+        assert!(rendered.iter().any(|&frame| frame != (0, 0)));
+        assert!(rendered == expected);
+    }
+
+    // Runs `program` from $0400, out of the way of the tone's sample
+    fn run_program(mut apu: Apu, program: &[u8]) -> Apu {
+        for (i, &byte) in program.iter().enumerate() {
+            apu.write_ram(0x0400 + i as u16, byte);
+        }
+        apu.smp.reg_pc = 0x0400;
+        apu
+    }
+
+    #[test]
+    fn detects_a_looping_program() {
+        // BRA $0400 over a held note, with the smallest echo buffer. The last thing to come
+        //  back round is then the DSP's rate counter, which takes 0.96s to wrap.
+        let mut apu = run_program(playing_apu(), &[0x2f, 0xfe]);
+        apu.dsp.set_echo_delay(0);
+        assert_eq!(apu.detect_loop(5), Some(Duration::from_millis(960)));
+    }
+
+    #[test]
+    fn does_not_detect_a_non_looping_program() {
+        // A 24-bit counter at $10-$12, which takes well over a minute to wrap
+        let mut apu = run_program(
+            playing_apu(),
+            &[
+                0xab, 0x10, // INC $10
+                0xd0, 0xfc, // BNE $0400
+                0xab, 0x11, // INC $11
+                0xd0, 0xf8, // BNE $0400
+                0xab, 0x12, // INC $12
+                0x2f, 0xf4, // BRA $0400
+            ],
+        );
+        assert_eq!(apu.detect_loop(5), None);
+        assert_ne!(apu.read_ram(0x0011), 0);
+    }
+
+    #[test]
+    fn renders_f32_samples() {
+        let mut expected = vec![(0, 0); 1000];
+        playing_apu().render(&mut expected);
+        let mut rendered = vec![(0.0, 0.0); 1000];
+        playing_apu().render_f32(&mut rendered);
+
+        assert!(expected.iter().any(|&frame| frame != (0, 0)));
+        for (&(left, right), &(left_f32, right_f32)) in expected.iter().zip(rendered.iter()) {
+            assert_eq!(left_f32, left as f32 / 32768.0);
+            assert_eq!(right_f32, right as f32 / 32768.0);
+            assert!((-1.0..1.0).contains(&left_f32));
+        }
+    }
+
+    #[test]
+    fn sample_iterator_matches_render() {
+        let mut expected = vec![(0, 0); 1000];
+        playing_apu().render(&mut expected);
+
+        let mut apu = playing_apu();
+        let mut rendered: Vec<_> = apu.samples().take(600).collect();
+        // Picking up again with a new iterator carries straight on
+        rendered.extend(apu.samples().take(400));
+        assert!(rendered == expected);
+    }
+
+    #[test]
+    fn skipping_matches_rendering() {
+        let mut skipped = playing_apu();
+        skipped.skip_samples(10000);
+        let mut rendered = playing_apu();
+        rendered.render(&mut vec![(0, 0); 10000]);
+        assert!(skipped.save_state() == rendered.save_state());
+
+        let mut expected = vec![(0, 0); 1000];
+        rendered.render(&mut expected);
+        let mut buffer = vec![(0, 0); 1000];
+        skipped.render(&mut buffer);
+        assert!(buffer == expected);
+    }
+
+    #[test]
+    fn render_silent_matches_rendering_without_growing() {
+        let mut silent = playing_apu();
+        silent.render_silent(SAMPLES_BATCH_LEN * 2);
+        let capacity = silent.dsp.output_buffer.capacity();
+        silent.render_silent(100000 - SAMPLES_BATCH_LEN * 2);
+        assert_eq!(silent.dsp.output_buffer.capacity(), capacity);
+
+        let mut rendered = playing_apu();
+        rendered.render(&mut vec![(0, 0); 100000]);
+        assert!(silent.save_state() == rendered.save_state());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn render_fast_is_faster_and_ends_up_in_step() {
+        use std::time::{Duration, Instant};
+
+        // The quickest of a few tries, to keep scheduling noise out of it
+        let time = |render: &dyn Fn(&mut Apu)| {
+            (0..3)
+                .map(|_| {
+                    let mut apu = playing_apu();
+                    let start = Instant::now();
+                    render(&mut apu);
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO)
+        };
+        let fast_time = time(&|apu| apu.render_fast(32000));
+        let normal_time = time(&|apu| apu.render(&mut vec![(0, 0); 32000]));
+        assert!(fast_time < normal_time);
+
+        let mut fast = playing_apu();
+        fast.render_fast(32000);
+        let mut normal = playing_apu();
+        normal.render(&mut vec![(0, 0); 32000]);
+        // Only the voices' last outputs differ, as they weren't interpolated
+        assert_eq!(fast.smp.registers(), normal.smp.registers());
+        assert_eq!(fast.samples_rendered(), normal.samples_rendered());
+        assert!(fast.ram[..] == normal.ram[..]);
+        for voice in 0..8 {
+            let envelope = fast.dsp.voice_envelope(voice);
+            assert_eq!(envelope, normal.dsp.voice_envelope(voice));
+        }
+        let resampling_mode = fast.dsp.resampling_mode();
+        assert!(matches!(resampling_mode, ResamplingMode::Gaussian));
+    }
+
+    #[test]
+    fn interleaved_matches_render() {
+        let mut expected = vec![(0, 0); 5000];
+        playing_apu().render(&mut expected);
+
+        // A trailing odd sample is left alone
+        let mut interleaved = vec![0x1234; 10001];
+        playing_apu().render_interleaved(&mut interleaved);
+        assert_eq!(interleaved[10000], 0x1234);
+        let frames = interleaved.chunks_exact(2).map(|lr| (lr[0], lr[1]));
+        assert!(frames.eq(expected.iter().copied()));
+    }
+
+    #[test]
+    fn render_until_stops_when_told() {
+        let mut expected = vec![(0, 0); 1000];
+        playing_apu().render(&mut expected);
+
+        let mut apu = playing_apu();
+        let mut out = vec![(1, 1)];
+        let mut calls = 0;
+        apu.render_until(&mut out, |num_frames| {
+            assert_eq!(num_frames, calls);
+            calls += 1;
+            num_frames < 1000
+        });
+        assert_eq!(calls, 1001);
+        // Appended after what was already there
+        assert_eq!(out.len(), 1001);
+        assert!(out[1..] == expected[..]);
+        assert_eq!(apu.samples_rendered(), 1000);
+    }
+
+    #[test]
+    fn fills_ram_with_the_power_on_pattern() {
+        let mut apu = Apu::new();
+        assert!(apu.ram.iter().all(|&value| value == 0));
+
+        apu.set_power_on_ram_pattern(PowerOnPattern::HighLow);
+        for (i, run) in apu.ram.chunks_exact(0x20).enumerate() {
+            let expected = if i % 2 == 0 { 0x00 } else { 0xff };
+            assert!(run.iter().all(|&value| value == expected));
+        }
+
+        // ...and again after a reset scribbles over it
+        let pattern = apu.ram.clone();
+        apu.render_seconds(0.1);
+        assert!(apu.ram[..] != pattern[..]);
+        apu.reset();
+        assert!(apu.ram[..] == pattern[..]);
+
+        let mut custom = Box::new([0; RAM_LEN]);
+        custom[0x1234] = 0x56;
+        apu.set_power_on_ram_pattern(PowerOnPattern::Custom(custom));
+        assert_eq!(apu.read_ram(0x1234), 0x56);
+        assert_eq!(apu.read_ram(0x0020), 0);
+    }
+
+    #[test]
+    fn stereo_modes_narrow_the_output() {
+        let render = |mode| {
+            let mut apu = tone_apu();
+            for voice in 0..8 {
+                apu.dsp.set_voice_volume(voice, 0x40, 0);
+            }
+            apu.dsp.key_on(0xff);
+            apu.set_stereo_mode(mode);
+            let mut buffer = vec![(0, 0); 500];
+            apu.render(&mut buffer);
+            // Only the output is changed, not what the DSP thinks it's playing
+            assert_eq!(apu.dsp.get_voice_volume(0), (0x40, 0));
+            buffer
+        };
+
+        let full = render(StereoMode::Full);
+        assert!(full.iter().any(|&(left, _)| left != 0));
+        assert!(full.iter().all(|&(_, right)| right == 0));
+
+        let mono = render(StereoMode::Mono);
+        for (&(left, _), &(mono_left, mono_right)) in full.iter().zip(mono.iter()) {
+            assert_eq!(mono_left, mono_right);
+            assert_eq!(mono_left, left / 2);
+        }
+
+        let narrow = render(StereoMode::Narrow(0.5));
+        for (&(left, _), &(narrow_left, narrow_right)) in full.iter().zip(narrow.iter()) {
+            assert_eq!(narrow_left, (left as f32 * 0.75) as i16);
+            assert_eq!(narrow_right, (left as f32 * 0.25) as i16);
+        }
+    }
+
+    #[test]
+    fn fades_out() {
+        let mut expected = vec![(0, 0); 3000];
+        playing_apu().render(&mut expected);
+
+        let mut apu = playing_apu();
+        let mut buffer = vec![(0, 0); 3000];
+        apu.render_with_fade(&mut buffer[..1500], 0, 1000, 1000);
+        apu.render_with_fade(&mut buffer[1500..], 1500, 1000, 1000);
+
+        assert!(buffer[..1000] == expected[..1000]);
+        // Halfway through the fade, it's at half volume
+        assert_eq!(buffer[1500].0, expected[1500].0 / 2);
+        assert!(expected[2000..].iter().any(|&frame| frame != (0, 0)));
+        assert!(buffer[2000..].iter().all(|&frame| frame == (0, 0)));
+    }
+
+    #[test]
+    fn render_exact_keeps_leftover_frames() {
+        let sizes = [1, 3, 4095, 0, 1, 4095, 3];
+        let mut expected = vec![(0, 0); sizes.iter().sum()];
+        playing_apu().render_exact(&mut expected);
+
+        let mut apu = playing_apu();
+        let mut rendered = Vec::new();
+        for &size in sizes.iter() {
+            let mut buffer = vec![(0, 0); size];
+            apu.render_exact(&mut buffer);
+            rendered.extend_from_slice(&buffer);
+        }
+        assert!(rendered == expected);
+        assert_eq!(apu.samples_rendered(), expected.len() as u64);
+    }
+
+    #[test]
+    fn tracks_the_playback_position() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.position(), Duration::ZERO);
+
+        apu.render_seconds(1.0);
+        assert_eq!(apu.samples_rendered(), 32000);
+        assert_eq!(apu.position(), Duration::from_secs(1));
+
+        apu.skip_samples(16000);
+        let mut buffer = [(0.0, 0.0); 8000];
+        apu.render_f32(&mut buffer);
+        assert_eq!(apu.position(), Duration::from_millis(1750));
+
+        apu.reset();
+        assert_eq!(apu.position(), Duration::ZERO);
+    }
+
+    #[test]
+    fn clamps_the_output_rate() {
+        let mut apu = Apu::new();
+        apu.set_output_sample_rate(0);
+        assert_eq!(apu.output_sample_rate(), MIN_OUTPUT_SAMPLE_RATE);
+        assert_eq!(apu.render_seconds(0.01).len(), 10);
+
+        apu.set_output_sample_rate(u32::MAX);
+        assert_eq!(apu.output_sample_rate(), MAX_OUTPUT_SAMPLE_RATE);
+    }
+}
@@ -0,0 +1,219 @@
+// Operand placeholders:
+//  {dN} - direct page address from byte N
+//  {iN} - immediate value from byte N
+//  {rN} - relative branch target from byte N
+//  {a}  - absolute address from bytes 1-2
+//  {m}  - absolute address and bit number (mem.bit) from bytes 1-2
+use alloc::string::String;
+
+use super::apu::RAM_LEN;
+
+#[rustfmt::skip]
+static OPCODES: [&str; 256] = [
+    // 0x00
+    "NOP", "TCALL 0", "SET1 {d1}.0", "BBS {d1}.0, {r2}",
+    "OR A, {d1}", "OR A, {a}", "OR A, (X)", "OR A, [{d1}+X]",
+    "OR A, {i1}", "OR {d2}, {d1}", "OR1 C, {m}", "ASL {d1}",
+    "ASL {a}", "PUSH PSW", "TSET1 {a}", "BRK",
+    // 0x10
+    "BPL {r1}", "TCALL 1", "CLR1 {d1}.0", "BBC {d1}.0, {r2}",
+    "OR A, {d1}+X", "OR A, {a}+X", "OR A, {a}+Y", "OR A, [{d1}]+Y",
+    "OR {d2}, {i1}", "OR (X), (Y)", "DECW {d1}", "ASL {d1}+X",
+    "ASL A", "DEC X", "CMP X, {a}", "JMP [{a}+X]",
+    // 0x20
+    "CLRP", "TCALL 2", "SET1 {d1}.1", "BBS {d1}.1, {r2}",
+    "AND A, {d1}", "AND A, {a}", "AND A, (X)", "AND A, [{d1}+X]",
+    "AND A, {i1}", "AND {d2}, {d1}", "OR1 C, /{m}", "ROL {d1}",
+    "ROL {a}", "PUSH A", "CBNE {d1}, {r2}", "BRA {r1}",
+    // 0x30
+    "BMI {r1}", "TCALL 3", "CLR1 {d1}.1", "BBC {d1}.1, {r2}",
+    "AND A, {d1}+X", "AND A, {a}+X", "AND A, {a}+Y", "AND A, [{d1}]+Y",
+    "AND {d2}, {i1}", "AND (X), (Y)", "INCW {d1}", "ROL {d1}+X",
+    "ROL A", "INC X", "CMP X, {d1}", "CALL {a}",
+    // 0x40
+    "SETP", "TCALL 4", "SET1 {d1}.2", "BBS {d1}.2, {r2}",
+    "EOR A, {d1}", "EOR A, {a}", "EOR A, (X)", "EOR A, [{d1}+X]",
+    "EOR A, {i1}", "EOR {d2}, {d1}", "AND1 C, {m}", "LSR {d1}",
+    "LSR {a}", "PUSH X", "TCLR1 {a}", "PCALL {d1}",
+    // 0x50
+    "BVC {r1}", "TCALL 5", "CLR1 {d1}.2", "BBC {d1}.2, {r2}",
+    "EOR A, {d1}+X", "EOR A, {a}+X", "EOR A, {a}+Y", "EOR A, [{d1}]+Y",
+    "EOR {d2}, {i1}", "EOR (X), (Y)", "CMPW YA, {d1}", "LSR {d1}+X",
+    "LSR A", "MOV X, A", "CMP Y, {a}", "JMP {a}",
+    // 0x60
+    "CLRC", "TCALL 6", "SET1 {d1}.3", "BBS {d1}.3, {r2}",
+    "CMP A, {d1}", "CMP A, {a}", "CMP A, (X)", "CMP A, [{d1}+X]",
+    "CMP A, {i1}", "CMP {d2}, {d1}", "AND1 C, /{m}", "ROR {d1}",
+    "ROR {a}", "PUSH Y", "DBNZ {d1}, {r2}", "RET",
+    // 0x70
+    "BVS {r1}", "TCALL 7", "CLR1 {d1}.3", "BBC {d1}.3, {r2}",
+    "CMP A, {d1}+X", "CMP A, {a}+X", "CMP A, {a}+Y", "CMP A, [{d1}]+Y",
+    "CMP {d2}, {i1}", "CMP (X), (Y)", "ADDW YA, {d1}", "ROR {d1}+X",
+    "ROR A", "MOV A, X", "CMP Y, {d1}", "RETI",
+    // 0x80
+    "SETC", "TCALL 8", "SET1 {d1}.4", "BBS {d1}.4, {r2}",
+    "ADC A, {d1}", "ADC A, {a}", "ADC A, (X)", "ADC A, [{d1}+X]",
+    "ADC A, {i1}", "ADC {d2}, {d1}", "EOR1 C, {m}", "DEC {d1}",
+    "DEC {a}", "MOV Y, {i1}", "POP PSW", "MOV {d2}, {i1}",
+    // 0x90
+    "BCC {r1}", "TCALL 9", "CLR1 {d1}.4", "BBC {d1}.4, {r2}",
+    "ADC A, {d1}+X", "ADC A, {a}+X", "ADC A, {a}+Y", "ADC A, [{d1}]+Y",
+    "ADC {d2}, {i1}", "ADC (X), (Y)", "SUBW YA, {d1}", "DEC {d1}+X",
+    "DEC A", "MOV X, SP", "DIV YA, X", "XCN A",
+    // 0xa0
+    "EI", "TCALL 10", "SET1 {d1}.5", "BBS {d1}.5, {r2}",
+    "SBC A, {d1}", "SBC A, {a}", "SBC A, (X)", "SBC A, [{d1}+X]",
+    "SBC A, {i1}", "SBC {d2}, {d1}", "MOV1 C, {m}", "INC {d1}",
+    "INC {a}", "CMP Y, {i1}", "POP A", "MOV (X)+, A",
+    // 0xb0
+    "BCS {r1}", "TCALL 11", "CLR1 {d1}.5", "BBC {d1}.5, {r2}",
+    "SBC A, {d1}+X", "SBC A, {a}+X", "SBC A, {a}+Y", "SBC A, [{d1}]+Y",
+    "SBC {d2}, {i1}", "SBC (X), (Y)", "MOVW YA, {d1}", "INC {d1}+X",
+    "INC A", "MOV SP, X", "DAS A", "MOV A, (X)+",
+    // 0xc0
+    "DI", "TCALL 12", "SET1 {d1}.6", "BBS {d1}.6, {r2}",
+    "MOV {d1}, A", "MOV {a}, A", "MOV (X), A", "MOV [{d1}+X], A",
+    "CMP X, {i1}", "MOV {a}, X", "MOV1 {m}, C", "MOV {d1}, Y",
+    "MOV {a}, Y", "MOV X, {i1}", "POP X", "MUL YA",
+    // 0xd0
+    "BNE {r1}", "TCALL 13", "CLR1 {d1}.6", "BBC {d1}.6, {r2}",
+    "MOV {d1}+X, A", "MOV {a}+X, A", "MOV {a}+Y, A", "MOV [{d1}]+Y, A",
+    "MOV {d1}, X", "MOV {d1}+Y, X", "MOVW {d1}, YA", "MOV {d1}+X, Y",
+    "DEC Y", "MOV A, Y", "CBNE {d1}+X, {r2}", "DAA A",
+    // 0xe0
+    "CLRV", "TCALL 14", "SET1 {d1}.7", "BBS {d1}.7, {r2}",
+    "MOV A, {d1}", "MOV A, {a}", "MOV A, (X)", "MOV A, [{d1}+X]",
+    "MOV A, {i1}", "MOV X, {a}", "NOT1 {m}", "MOV Y, {d1}",
+    "MOV Y, {a}", "NOTC", "POP Y", "SLEEP",
+    // 0xf0
+    "BEQ {r1}", "TCALL 15", "CLR1 {d1}.7", "BBC {d1}.7, {r2}",
+    "MOV A, {d1}+X", "MOV A, {a}+X", "MOV A, {a}+Y", "MOV A, [{d1}]+Y",
+    "MOV X, {d1}", "MOV X, {d1}+Y", "MOV {d2}, {d1}", "MOV Y, {d1}+X",
+    "INC Y", "MOV Y, A", "DBNZ Y, {r1}", "STOP",
+];
+
+fn operand_len(placeholder: &str) -> u16 {
+    match placeholder {
+        "a" | "m" => 2,
+        _ => placeholder[1..].parse().unwrap(),
+    }
+}
+
+/// Disassembles the instruction at `addr`, returning its text and its length in bytes.
+/// Operands past the end of the address space wrap round to the start.
+pub fn disassemble(ram: &[u8; RAM_LEN], addr: u16) -> (String, u16) {
+    let read = |offset: u16| ram[addr.wrapping_add(offset) as usize];
+    let read_word = |offset: u16| (read(offset) as u16) | ((read(offset + 1) as u16) << 8);
+
+    let template = OPCODES[read(0) as usize];
+
+    let mut len = 1;
+    for placeholder in template.split('{').skip(1) {
+        let placeholder = &placeholder[..placeholder.find('}').unwrap()];
+        len = len.max(1 + operand_len(placeholder));
+    }
+
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        ret.push_str(&rest[..start]);
+        let end = rest.find('}').unwrap();
+        let placeholder = &rest[start + 1..end];
+        let operand = match placeholder {
+            "a" => format!("${:04x}", read_word(1)),
+            "m" => {
+                let value = read_word(1);
+                format!("${:04x}.{}", value & 0x1fff, value >> 13)
+            }
+            _ => {
+                let value = read(operand_len(placeholder));
+                match &placeholder[..1] {
+                    "d" => format!("${:02x}", value),
+                    "i" => format!("#${:02x}", value),
+                    "r" => format!(
+                        "${:04x}",
+                        addr.wrapping_add(len)
+                            .wrapping_add(((value as i8) as i16) as u16)
+                    ),
+                    _ => unreachable!(),
+                }
+            }
+        };
+        ret.push_str(&operand);
+        rest = &rest[end + 1..];
+    }
+    ret.push_str(rest);
+
+    (ret, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::apu::Apu;
+    use super::*;
+
+    #[test]
+    fn disassembles_the_ipl_rom() {
+        let apu = Apu::new();
+        let mut ram = [0; RAM_LEN];
+        for (addr, byte) in ram.iter_mut().enumerate().skip(0xffc0) {
+            *byte = apu.peek_u8(addr as u16);
+        }
+
+        let expected = [
+            (0xffc0, "MOV X, #$ef"),
+            (0xffc2, "MOV SP, X"),
+            (0xffc3, "MOV A, #$00"),
+            (0xffc5, "MOV (X), A"),
+            (0xffc6, "DEC X"),
+            (0xffc7, "BNE $ffc5"),
+            (0xffc9, "MOV $f4, #$aa"),
+            (0xffcc, "MOV $f5, #$bb"),
+            (0xffcf, "CMP $f4, #$cc"),
+            (0xffd2, "BNE $ffcf"),
+            (0xffd4, "BRA $ffef"),
+            (0xffd6, "MOV Y, $f4"),
+            (0xffd8, "BNE $ffd6"),
+            (0xffda, "CMP Y, $f4"),
+            (0xffdc, "BNE $ffe9"),
+            (0xffde, "MOV A, $f5"),
+            (0xffe0, "MOV $f4, Y"),
+            (0xffe2, "MOV [$00]+Y, A"),
+            (0xffe4, "INC Y"),
+            (0xffe5, "BNE $ffda"),
+            (0xffe7, "INC $01"),
+            (0xffe9, "BPL $ffda"),
+            (0xffeb, "CMP Y, $f4"),
+            (0xffed, "BPL $ffda"),
+            (0xffef, "MOVW YA, $f6"),
+            (0xfff1, "MOVW $00, YA"),
+            (0xfff3, "MOVW YA, $f4"),
+            (0xfff5, "MOV $f4, A"),
+            (0xfff7, "MOV A, Y"),
+            (0xfff8, "MOV X, A"),
+            (0xfff9, "BNE $ffd6"),
+            (0xfffb, "JMP [$0000+X]"),
+        ];
+        let mut addr = 0xffc0;
+        for &(expected_addr, expected_text) in expected.iter() {
+            assert_eq!(addr, expected_addr);
+            let (text, len) = disassemble(&ram, addr);
+            assert_eq!(text, expected_text);
+            addr += len;
+        }
+        // The reset vector follows
+        assert_eq!(addr, 0xfffe);
+    }
+
+    #[test]
+    fn wraps_operands_round_the_address_space() {
+        let mut ram = [0; RAM_LEN];
+        ram[0xffff] = 0x8f; // MOV dp, #imm
+        ram[0x0000] = 0x12;
+        ram[0x0001] = 0x34;
+        assert_eq!(
+            disassemble(&ram, 0xffff),
+            (String::from("MOV $34, #$12"), 3)
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use alloc::vec::Vec;
+
+use super::brr_block_decoder::BrrBlockDecoder;
+
+pub const BLOCK_LEN: usize = 9;
+pub const SAMPLES_PER_BLOCK: usize = 16;
+
+const MAX_BLOCKS: usize = 0x10000 / BLOCK_LEN + 1;
+
+/// Decodes a single BRR block. `prev` holds the last two decoded samples (most recent
+/// first), and the updated pair is returned alongside the decoded PCM.
+pub fn decode_brr_block(
+    block: &[u8; BLOCK_LEN],
+    prev: (i16, i16),
+) -> ([i16; SAMPLES_PER_BLOCK], (i16, i16)) {
+    let mut decoder = BrrBlockDecoder::new();
+    decoder.reset(prev.0, prev.1);
+    decoder.read(block);
+
+    let mut samples = [0; SAMPLES_PER_BLOCK];
+    for sample in samples.iter_mut() {
+        *sample = decoder.read_next_sample();
+    }
+    (samples, decoder.last_samples())
+}
+
+/// Decodes a whole sample starting at `start`, up to and including the first block with
+/// the END flag set. Loop points aren't followed, so looped samples come out once.
+pub fn decode_brr(ram: &[u8], start: u16) -> Vec<i16> {
+    let mut ret = Vec::new();
+    let mut decoder = BrrBlockDecoder::new();
+    let mut address = start;
+    for _ in 0..MAX_BLOCKS {
+        let mut block = [0; BLOCK_LEN];
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = ram[(address.wrapping_add(i as u16) as usize) % ram.len()];
+        }
+        decoder.read(&block);
+
+        while !decoder.is_finished() {
+            ret.push(decoder.read_next_sample());
+        }
+
+        if decoder.is_end {
+            break;
+        }
+        address = address.wrapping_add(BLOCK_LEN as u16);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_a_block() {
+        // Shift 11, no filter: each nybble comes out as itself times 2048
+        let block = [0xb0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        let (samples, prev) = decode_brr_block(&block, (0, 0));
+        let expected: Vec<i16> = (0..16).map(|i| ((i << 12) as i16) >> 1).collect();
+        assert_eq!(samples[..], expected[..]);
+        assert_eq!(prev, (-2048, -4096));
+
+        // Filter 1 decays towards zero from the previous sample
+        let block = [0xc4, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (samples, prev) = decode_brr_block(&block, (1000, 0));
+        assert_eq!(samples[..3], [936, 876, 820]);
+        assert_eq!(prev, (samples[15], samples[14]));
+    }
+
+    #[test]
+    fn decodes_up_to_the_end_block() {
+        let mut ram = vec![0; 0x10000];
+        ram[0x0100] = 0xb0;
+        ram[0x0109] = 0xb1;
+        ram[0x0112] = 0xb0;
+        ram[0x0113] = 0x77;
+        assert_eq!(decode_brr(&ram, 0x0100), vec![0; 32]);
+
+        // Samples running off the end of RAM wrap round to the start
+        ram[0xfffc] = 0xb0;
+        ram[0x0005] = 0xb1;
+        assert_eq!(decode_brr(&ram, 0xfffc).len(), 32);
+    }
+}
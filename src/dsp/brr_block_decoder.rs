@@ -1,3 +1,5 @@
+use super::super::apu::LoadStateError;
+use super::super::state::{StateReader, StateWriter};
 use super::dsp_helpers;
 
 pub struct BrrBlockDecoder {
@@ -29,6 +31,37 @@ impl BrrBlockDecoder {
         self.last_last_sample = last_last_sample;
     }
 
+    pub fn last_samples(&self) -> (i16, i16) {
+        (self.last_sample, self.last_last_sample)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.is_end);
+        writer.write_bool(self.is_looping);
+        for sample in self.samples.iter() {
+            writer.write_i16(*sample);
+        }
+        writer.write_i32(self.sample_index);
+        writer.write_i16(self.last_sample);
+        writer.write_i16(self.last_last_sample);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        self.is_end = reader.read_bool()?;
+        self.is_looping = reader.read_bool()?;
+        for sample in self.samples.iter_mut() {
+            *sample = reader.read_i16()?;
+        }
+        // A finished block is always replaced straight away, so 16 never gets saved
+        self.sample_index = reader.read_i32()?;
+        if self.sample_index < 0 || self.sample_index >= 16 {
+            return Err(LoadStateError::Invalid);
+        }
+        self.last_sample = reader.read_i16()?;
+        self.last_last_sample = reader.read_i16()?;
+        Ok(())
+    }
+
     pub fn read(&mut self, buf: &[u8]) {
         let mut buf_pos = 0;
 
@@ -1,8 +1,11 @@
-use std::collections::VecDeque;
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 
-use super::super::apu::Apu;
-use super::super::spc::spc::{Spc, REG_LEN};
+use super::super::apu::{LoadStateError, RAM_LEN};
+#[cfg(feature = "std")]
+use super::super::spc::spc::Spc;
+use super::super::state::{StateReader, StateWriter};
 use super::dsp_helpers;
 use super::filter::Filter;
 use super::voice::{ResamplingMode, Voice};
@@ -10,6 +13,8 @@ use super::voice::{ResamplingMode, Voice};
 pub const SAMPLE_RATE: usize = 32000;
 pub const BUFFER_LEN: usize = SAMPLE_RATE * 2;
 
+pub const REG_LEN: usize = 128;
+
 const NUM_VOICES: usize = 8;
 
 const COUNTER_RANGE: i32 = 30720;
@@ -53,15 +58,71 @@ static COUNTER_OFFSETS: [i32; 32] = [
     536, 0, 1040, 536, 0, 1040, 536, 0, 1040, 0, 0,
 ];
 
-pub struct Dsp<'apu> {
-    emulator: Rc<Apu<'apu>>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    /// ADSR is disabled and the voice is following its GAIN register.
+    Gain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeState {
+    /// The current 11-bit envelope level.
+    pub level: u16,
+    pub phase: EnvelopePhase,
+}
+
+/// Which part of the final mix ends up in the output buffer. The echo buffer is fed the
+/// same way regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputTap {
+    Mixed,
+    DryOnly,
+    EchoOnly,
+}
+
+/// The memory the DSP sees while it renders: ARAM for samples, plus the echo sandbox
+/// if `Apu` has one, which takes the echo buffer's reads and writes instead. Echo writes
+/// are dropped while TEST has RAM writes disabled.
+pub(crate) struct DspMemory<'a> {
+    pub ram: &'a mut [u8; RAM_LEN],
+    pub echo_sandbox: Option<&'a mut [u8; RAM_LEN]>,
+    pub is_write_enabled: bool,
+}
+
+impl DspMemory<'_> {
+    fn read_echo(&self, address: u16) -> u8 {
+        match self.echo_sandbox {
+            Some(ref sandbox) => sandbox[address as usize],
+            None => self.ram[address as usize],
+        }
+    }
 
+    fn write_echo(&mut self, address: u16, value: u8) {
+        if !self.is_write_enabled {
+            return;
+        }
+        match self.echo_sandbox {
+            Some(ref mut sandbox) => sandbox[address as usize] = value,
+            None => self.ram[address as usize] = value,
+        }
+    }
+}
+
+pub struct Dsp {
     pub voices: Vec<Box<Voice>>,
 
     left_filter: Filter,
     right_filter: Filter,
+    /// Frames waiting to be picked up. It's preallocated, and `Apu` only ever asks for a
+    /// batch at a time, so steady-state rendering never reallocates it.
     pub output_buffer: VecDeque<(i16, i16)>,
 
+    regs: [u8; REG_LEN],
+
     vol_left: u8,
     vol_right: u8,
     echo_vol_left: u8,
@@ -74,27 +135,31 @@ pub struct Dsp<'apu> {
     echo_delay: u8,
 
     counter: i32,
+    kon_pending: u8,
+    kof_pending: u8,
 
     cycles_since_last_flush: usize,
-    is_flushing: bool,
     noise: i32,
     echo_pos: i32,
     echo_length: i32,
 
     resampling_mode: ResamplingMode,
+    master_gain: f32,
+    output_tap: OutputTap,
+    is_echo_filter_bypassed: bool,
 }
 
-impl<'apu> Dsp<'apu> {
-    pub fn new(emulator: Rc<Apu<'apu>>) -> Box<Dsp> {
+impl Dsp {
+    pub fn new() -> Dsp {
         let resampling_mode = ResamplingMode::Gaussian;
-        let mut ret = Rc::new_cyclic(Dsp {
-            emulator: emulator,
-
-            voices: vec![Voice::new(); NUM_VOICES],
+        let mut ret = Dsp {
+            voices: Vec::with_capacity(NUM_VOICES),
 
             left_filter: Filter::new(),
             right_filter: Filter::new(),
-            output_buffer: VecDeque::new(),
+            output_buffer: VecDeque::with_capacity(BUFFER_LEN),
+
+            regs: [0; REG_LEN],
 
             vol_left: 0x89,
             vol_right: 0x9c,
@@ -108,35 +173,58 @@ impl<'apu> Dsp<'apu> {
             echo_delay: 0x0e,
 
             counter: 0,
+            kon_pending: 0,
+            kof_pending: 0,
 
             cycles_since_last_flush: 0,
-            is_flushing: false,
             noise: 0x4000,
             echo_pos: 0,
             echo_length: 0,
 
-            resampling_mode: resampling_mode,
-        });
-        let ret_ptr = &mut *ret as *mut _;
+            resampling_mode,
+            master_gain: 1.0,
+            output_tap: OutputTap::Mixed,
+            is_echo_filter_bypassed: false,
+        };
         for _ in 0..NUM_VOICES {
-            ret.voices
-                .push(Box::new(Voice::new(ret_ptr, emulator, resampling_mode)));
-        }
-        ret.set_filter_coefficient(0x00, 0x80);
-        ret.set_filter_coefficient(0x01, 0xff);
-        ret.set_filter_coefficient(0x02, 0x9a);
-        ret.set_filter_coefficient(0x03, 0xff);
-        ret.set_filter_coefficient(0x04, 0x67);
-        ret.set_filter_coefficient(0x05, 0xff);
-        ret.set_filter_coefficient(0x06, 0x0f);
-        ret.set_filter_coefficient(0x07, 0xff);
+            ret.voices.push(Box::new(Voice::new(resampling_mode)));
+        }
+        ret.set_default_filter_coefficients();
         ret.set_resampling_mode(ResamplingMode::Gaussian);
         ret
     }
 
-    #[inline]
-    fn emulator(&self) -> &mut Apu {
-        unsafe { &mut (*self.emulator) }
+    pub fn reset(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.reset();
+        }
+
+        self.left_filter = Filter::new();
+        self.right_filter = Filter::new();
+        self.set_default_filter_coefficients();
+        self.output_buffer.clear();
+
+        self.regs = [0; REG_LEN];
+
+        self.vol_left = 0x89;
+        self.vol_right = 0x9c;
+        self.echo_vol_left = 0x9f;
+        self.echo_vol_right = 0x9c;
+        self.noise_clock = 0;
+        self.echo_write_enabled = false;
+        self.echo_feedback = 0;
+        self.source_dir = 0;
+        self.echo_start_address = Dsp::calculate_echo_start_address(0x60);
+        self.echo_delay = 0x0e;
+
+        self.counter = 0;
+        self.kon_pending = 0;
+        self.kof_pending = 0;
+
+        self.cycles_since_last_flush = 0;
+        self.noise = 0x4000;
+        self.echo_pos = 0;
+        self.echo_length = 0;
     }
 
     fn set_filter_coefficient(&mut self, index: i32, value: u8) {
@@ -144,6 +232,96 @@ impl<'apu> Dsp<'apu> {
         self.right_filter.coefficients[index as usize] = value;
     }
 
+    fn set_default_filter_coefficients(&mut self) {
+        self.set_filter_coefficient(0x00, 0x80);
+        self.set_filter_coefficient(0x01, 0xff);
+        self.set_filter_coefficient(0x02, 0x9a);
+        self.set_filter_coefficient(0x03, 0xff);
+        self.set_filter_coefficient(0x04, 0x67);
+        self.set_filter_coefficient(0x05, 0xff);
+        self.set_filter_coefficient(0x06, 0x0f);
+        self.set_filter_coefficient(0x07, 0xff);
+    }
+
+    pub fn get_echo_fir_coefficients(&self) -> [i8; 8] {
+        let mut ret = [0; 8];
+        for (out, &coefficient) in ret.iter_mut().zip(self.left_filter.coefficients.iter()) {
+            *out = coefficient as i8;
+        }
+        ret
+    }
+
+    pub fn set_echo_fir_coefficients(&mut self, coefficients: [i8; 8]) {
+        for (i, &coefficient) in coefficients.iter().enumerate() {
+            self.set_register(((i as u8) << 4) | 0x0f, coefficient as u8);
+        }
+    }
+
+    /// Voices with pitch modulation (PMON) enabled; voice 0 has nothing to modulate it,
+    /// so bit 0 always reads back as 0.
+    pub fn get_pitch_modulation_enabled(&self) -> u8 {
+        let mut ret = 0;
+        for (i, voice) in self.voices.iter().enumerate() {
+            if voice.pitch_mod {
+                ret |= 1 << i;
+            }
+        }
+        ret
+    }
+
+    pub fn set_pitch_modulation_enabled(&mut self, voice_mask: u8) {
+        self.set_register(0x2d, voice_mask);
+    }
+
+    /// Voices that feed the echo buffer (EON). Nothing is written back to the buffer while
+    /// FLG has echo writes disabled, whatever this is set to.
+    pub fn get_echo_enabled_voices(&self) -> u8 {
+        let mut ret = 0;
+        for (i, voice) in self.voices.iter().enumerate() {
+            if voice.echo_on {
+                ret |= 1 << i;
+            }
+        }
+        ret
+    }
+
+    pub fn set_echo_enabled_voices(&mut self, voice_mask: u8) {
+        self.set_register(0x4d, voice_mask);
+    }
+
+    pub fn get_noise_clock(&self) -> u8 {
+        self.noise_clock
+    }
+
+    /// Loads the noise LFSR so that its current output is `value` (the low bit is always 0).
+    pub fn set_noise_sample(&mut self, value: i16) {
+        self.noise = ((value as u16) >> 1) as i32;
+    }
+
+    /// Loads the 15-bit noise LFSR directly. The hardware powers up with 0x4000; a seed of
+    /// 0 leaves the generator stuck at silence.
+    pub fn set_noise_seed(&mut self, seed: u16) {
+        self.noise = (seed & 0x7fff) as i32;
+    }
+
+    pub fn master_gain(&self) -> f32 {
+        self.master_gain
+    }
+
+    /// Scales the final mix on its way into `output_buffer`. This is separate from the
+    /// MVOL registers, which belong to the driver.
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.master_gain = gain;
+    }
+
+    fn apply_master_gain(&self, value: i16) -> i16 {
+        Dsp::scale_by_gain(value, self.master_gain)
+    }
+
+    fn scale_by_gain(value: i16, gain: f32) -> i16 {
+        dsp_helpers::clamp(((value as f32) * gain) as i32) as i16
+    }
+
     pub fn resampling_mode(&self) -> ResamplingMode {
         self.resampling_mode
     }
@@ -155,21 +333,252 @@ impl<'apu> Dsp<'apu> {
         }
     }
 
+    pub fn is_voice_muted(&self, voice: usize) -> bool {
+        self.voices[voice].is_muted
+    }
+
+    pub fn set_voice_muted(&mut self, voice: usize, muted: bool) {
+        self.voices[voice].is_muted = muted;
+    }
+
+    pub fn is_voice_captured(&self, voice: usize) -> bool {
+        self.voices[voice].capture_buffer.is_some()
+    }
+
+    /// Starts or stops recording the voice's contribution to the main mix, after its own
+    /// volume, MVOL and master gain but without echo. Summing every voice's capture gives
+    /// back the dry mix, give or take clamping. Captured frames pile up until they're
+    /// taken, and disabling capture throws away any that haven't been.
+    pub fn set_voice_capture(&mut self, voice: usize, enabled: bool) {
+        let voice = &mut self.voices[voice];
+        if !enabled {
+            voice.capture_buffer = None;
+        } else if voice.capture_buffer.is_none() {
+            voice.capture_buffer = Some(Vec::new());
+        }
+    }
+
+    /// Hands over everything captured for the voice since the last call.
+    pub fn take_voice_samples(&mut self, voice: usize) -> Vec<(i16, i16)> {
+        match self.voices[voice].capture_buffer {
+            Some(ref mut capture_buffer) => core::mem::take(capture_buffer),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_voice_volume(&self, voice: usize) -> (i8, i8) {
+        let voice = &self.voices[voice];
+        (voice.vol_left as i8, voice.vol_right as i8)
+    }
+
+    pub fn set_voice_volume(&mut self, voice: usize, left: i8, right: i8) {
+        let base = (voice as u8) << 4;
+        self.set_register(base, left as u8);
+        self.set_register(base | 0x01, right as u8);
+    }
+
+    /// Where the voice sits between -1.0 (full left) and 1.0 (full right), going by the
+    /// magnitudes of VOL(L) and VOL(R). A silent voice counts as centred.
+    pub fn voice_pan(&self, voice: usize) -> f32 {
+        let (left, right) = self.get_voice_volume(voice);
+        let left = (left as f32).abs();
+        let right = (right as f32).abs();
+        if left + right == 0.0 {
+            return 0.0;
+        }
+        (right - left) / (left + right)
+    }
+
+    /// Rewrites VOL(L) and VOL(R) so the voice sits at `pan` without changing their
+    /// combined magnitude. Inverted (negative) volumes stay inverted.
+    pub fn set_voice_pan(&mut self, voice: usize, pan: f32) {
+        let (left, right) = self.get_voice_volume(voice);
+        let total = (left as f32).abs() + (right as f32).abs();
+        let pan = pan.clamp(-1.0, 1.0);
+        // A loud voice panned hard just tops out at full volume on one side
+        let new_left = ((total * (1.0 - pan) / 2.0 + 0.5) as i32).min(127);
+        let new_right = ((total * (1.0 + pan) / 2.0 + 0.5) as i32).min(127);
+        let new_left = if left < 0 { -new_left } else { new_left };
+        let new_right = if right < 0 { -new_right } else { new_right };
+        self.set_voice_volume(voice, new_left as i8, new_right as i8);
+    }
+
+    /// The voice's 14-bit pitch, from P(L) and P(H). 0x1000 plays samples back at 32kHz.
+    pub fn get_voice_pitch(&self, voice: usize) -> u16 {
+        let base = voice << 4;
+        (((self.regs[base | 0x03] & 0x3f) as u16) << 8) | (self.regs[base | 0x02] as u16)
+    }
+
+    pub fn set_voice_pitch(&mut self, voice: usize, pitch: u16) {
+        let base = (voice as u8) << 4;
+        self.set_register(base | 0x02, pitch as u8);
+        self.set_register(base | 0x03, ((pitch >> 8) as u8) & 0x3f);
+    }
+
+    pub fn voice_envelope(&self, voice: usize) -> EnvelopeState {
+        let envelope = &self.voices[voice].envelope;
+        EnvelopeState {
+            level: envelope.level as u16,
+            phase: envelope.phase(),
+        }
+    }
+
+    /// Keys on every voice in the mask, as a write to KON would. Like a KON write it's
+    /// latched, so the voices enter Attack at the start of the next sample rendered, but
+    /// KON reads back clear straight away.
+    pub fn key_on(&mut self, voices: u8) {
+        self.set_register(0x4c, voices);
+        self.regs[0x4c] = 0;
+    }
+
+    /// Keys off every voice in the mask, as a write to KOFF would.
+    pub fn key_off(&mut self, voices: u8) {
+        self.set_register(0x5c, voices);
+    }
+
     fn calculate_echo_start_address(value: u8) -> u16 {
         (value as u16) << 8
     }
 
+    #[cfg(feature = "std")]
     pub fn set_state(&mut self, spc: &Spc) {
-        for i in 0..REG_LEN {
+        self.set_register_file(&spc.regs);
+    }
+
+    pub fn get_state(&self) -> [u8; REG_LEN] {
+        self.register_file()
+    }
+
+    /// All 128 registers as `get_register` would read them, laid out like `Spc::regs`.
+    pub fn register_file(&self) -> [u8; REG_LEN] {
+        let mut regs = self.regs;
+        for (i, voice) in self.voices.iter().enumerate() {
+            regs[(i << 4) | 0x08] = voice.envelope.envx;
+            regs[(i << 4) | 0x09] = voice.outx;
+        }
+        regs
+    }
+
+    /// Loads all 128 registers at once, the way an SPC snapshot restores them: KON keys
+    /// on the voices it names and KOF is stored without keying anything off.
+    pub fn set_register_file(&mut self, regs: &[u8; REG_LEN]) {
+        for (i, &value) in regs.iter().enumerate() {
             match i {
                 0x4c | 0x5c => (), // Do nothing
                 _ => {
-                    self.set_register(i as u8, spc.regs[i as usize]);
+                    self.set_register(i as u8, value);
                 }
             }
         }
 
-        self.set_kon(spc.regs[0x4c]);
+        self.set_kon(regs[0x4c]);
+        self.regs[0x4c] = regs[0x4c];
+        self.regs[0x5c] = regs[0x5c];
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for voice in self.voices.iter() {
+            voice.save_state(writer);
+        }
+
+        self.left_filter.save_state(writer);
+        self.right_filter.save_state(writer);
+        writer.write_u32(self.output_buffer.len() as u32);
+        for &(left, right) in self.output_buffer.iter() {
+            writer.write_i16(left);
+            writer.write_i16(right);
+        }
+
+        writer.write_bytes(&self.regs);
+
+        writer.write_u8(self.vol_left);
+        writer.write_u8(self.vol_right);
+        writer.write_u8(self.echo_vol_left);
+        writer.write_u8(self.echo_vol_right);
+        writer.write_u8(self.noise_clock);
+        writer.write_bool(self.echo_write_enabled);
+        writer.write_u8(self.echo_feedback);
+        writer.write_u8(self.source_dir);
+        writer.write_u16(self.echo_start_address);
+        writer.write_u8(self.echo_delay);
+
+        writer.write_i32(self.counter);
+        writer.write_u8(self.kon_pending);
+        writer.write_u8(self.kof_pending);
+
+        writer.write_u32(self.cycles_since_last_flush as u32);
+        writer.write_i32(self.noise);
+        writer.write_i32(self.echo_pos);
+        writer.write_i32(self.echo_length);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        for voice in self.voices.iter_mut() {
+            voice.load_state(reader)?;
+        }
+
+        self.left_filter.load_state(reader)?;
+        self.right_filter.load_state(reader)?;
+        let output_buffer_len = reader.read_u32()?;
+        self.output_buffer.clear();
+        for _ in 0..output_buffer_len {
+            self.output_buffer
+                .push_back((reader.read_i16()?, reader.read_i16()?));
+        }
+
+        reader.read_bytes(&mut self.regs)?;
+
+        self.vol_left = reader.read_u8()?;
+        self.vol_right = reader.read_u8()?;
+        self.echo_vol_left = reader.read_u8()?;
+        self.echo_vol_right = reader.read_u8()?;
+        self.noise_clock = reader.read_u8()? & 0x1f;
+        self.echo_write_enabled = reader.read_bool()?;
+        self.echo_feedback = reader.read_u8()?;
+        self.source_dir = reader.read_u8()?;
+        self.echo_start_address = reader.read_u16()?;
+        self.echo_delay = reader.read_u8()? & 0x0f;
+
+        self.counter = reader.read_i32()?.rem_euclid(COUNTER_RANGE);
+        self.kon_pending = reader.read_u8()?;
+        self.kof_pending = reader.read_u8()?;
+
+        self.cycles_since_last_flush = reader.read_u32()? as usize;
+        self.noise = reader.read_i32()?;
+        self.echo_pos = reader.read_i32()?;
+        self.echo_length = reader.read_i32()?;
+        Ok(())
+    }
+
+    pub(crate) fn set_echo_filter_bypassed(&mut self, bypassed: bool) {
+        self.is_echo_filter_bypassed = bypassed;
+    }
+
+    pub fn output_tap(&self) -> OutputTap {
+        self.output_tap
+    }
+
+    pub fn set_output_tap(&mut self, tap: OutputTap) {
+        self.output_tap = tap;
+    }
+
+    pub fn available_samples(&self) -> usize {
+        self.output_buffer.len()
+    }
+
+    /// Moves as many buffered frames into `out` as are available, up to its length, and
+    /// returns how many were written. Never runs the DSP.
+    pub fn take_samples(&mut self, out: &mut [(i16, i16)]) -> usize {
+        let count = out.len().min(self.output_buffer.len());
+        for (out, sample) in out.iter_mut().zip(self.output_buffer.drain(..count)) {
+            *out = sample;
+        }
+        count
+    }
+
+    /// Cycles that have been run but not yet turned into an output frame.
+    pub fn pending_cycles(&self) -> usize {
+        self.cycles_since_last_flush
     }
 
     pub fn cycles_callback(&mut self, num_cycles: usize) {
@@ -184,100 +593,158 @@ impl<'apu> Dsp<'apu> {
         (self.echo_delay as i32) * 0x800
     }
 
-    pub fn flush(&mut self) {
-        self.is_flushing = true;
+    pub fn get_echo_delay(&self) -> u8 {
+        self.echo_delay
+    }
+
+    pub fn set_echo_delay(&mut self, value: u8) {
+        self.set_register(0x7d, value);
+    }
+
+    pub fn get_echo_feedback(&self) -> i8 {
+        self.echo_feedback as i8
+    }
+
+    pub fn set_echo_feedback(&mut self, value: i8) {
+        self.set_register(0x0d, value as u8);
+    }
 
+    /// How much RAM the echo buffer covers. Even with EDL = 0 the DSP still reads and
+    /// writes a single 4-byte frame.
+    pub fn echo_buffer_size_bytes(&self) -> usize {
+        (self.calculate_echo_length() as usize).max(4)
+    }
+
+    pub(crate) fn flush(&mut self, memory: &mut DspMemory) {
         while self.cycles_since_last_flush > 64 {
-            if !self.read_counter(self.noise_clock as i32) {
-                let feedback = (self.noise << 13) ^ (self.noise << 14);
-                self.noise = (feedback & 0x4000) ^ (self.noise >> 1);
-            }
+            let output = self.render_frame(memory);
+            self.output_buffer.push_back(output);
+            self.cycles_since_last_flush -= 64;
+        }
+    }
 
-            let mut are_any_voices_solod = false;
-            for voice in self.voices.iter() {
-                if voice.is_solod {
-                    are_any_voices_solod = true;
-                    break;
-                }
+    pub(crate) fn step(&mut self, memory: &mut DspMemory) -> (i16, i16) {
+        self.render_frame(memory)
+    }
+
+    fn render_frame(&mut self, memory: &mut DspMemory) -> (i16, i16) {
+        let directory_base = self.sample_directory_base();
+        if (self.kon_pending | self.kof_pending) != 0 {
+            self.apply_pending_keys(memory.ram, directory_base);
+        }
+
+        if !self.read_counter(self.noise_clock as i32) {
+            let feedback = (self.noise << 13) ^ (self.noise << 14);
+            self.noise = (feedback & 0x4000) ^ (self.noise >> 1);
+        }
+
+        let mut are_any_voices_solod = false;
+        for voice in self.voices.iter() {
+            if voice.is_solod {
+                are_any_voices_solod = true;
+                break;
             }
+        }
 
-            let mut left_out = 0;
-            let mut right_out = 0;
-            let mut left_echo_out = 0;
-            let mut right_echo_out = 0;
-            let mut last_voice_out = 0;
-            for voice in self.voices.iter_mut() {
-                let output = voice.render_sample(last_voice_out, self.noise, are_any_voices_solod);
+        let mut left_out = 0;
+        let mut right_out = 0;
+        let mut left_echo_out = 0;
+        let mut right_echo_out = 0;
+        let mut last_voice_out = 0;
+        for voice in self.voices.iter_mut() {
+            let output = voice.render_sample(
+                memory.ram,
+                directory_base,
+                self.counter,
+                last_voice_out,
+                self.noise,
+                are_any_voices_solod,
+            );
 
-                left_out = dsp_helpers::clamp(left_out + output.left_out);
-                right_out = dsp_helpers::clamp(right_out + output.right_out);
+            if let Some(ref mut capture_buffer) = voice.capture_buffer {
+                let left = dsp_helpers::multiply_volume(output.left_out, self.vol_left);
+                let right = dsp_helpers::multiply_volume(output.right_out, self.vol_right);
+                capture_buffer.push((
+                    Dsp::scale_by_gain(dsp_helpers::clamp(left) as i16, self.master_gain),
+                    Dsp::scale_by_gain(dsp_helpers::clamp(right) as i16, self.master_gain),
+                ));
+            }
 
-                if voice.echo_on {
-                    left_echo_out = dsp_helpers::clamp(left_echo_out + output.left_out);
-                    right_echo_out = dsp_helpers::clamp(right_echo_out + output.right_out);
-                }
+            left_out = dsp_helpers::clamp(left_out + output.left_out);
+            right_out = dsp_helpers::clamp(right_out + output.right_out);
 
-                last_voice_out = output.last_voice_out;
+            if voice.echo_on {
+                left_echo_out = dsp_helpers::clamp(left_echo_out + output.left_out);
+                right_echo_out = dsp_helpers::clamp(right_echo_out + output.right_out);
             }
 
-            left_out = dsp_helpers::multiply_volume(left_out, self.vol_left);
-            right_out = dsp_helpers::multiply_volume(right_out, self.vol_right);
+            last_voice_out = output.last_voice_out;
+        }
 
-            let echo_address = (self.echo_start_address + (self.echo_pos as u16)) as u32;
-            let mut left_echo_in = (((((self.emulator().read_u8(echo_address + 1) as i32) << 8)
-                | (self.emulator().read_u8(echo_address) as i32))
-                as i16)
-                & !1) as i32;
-            let mut right_echo_in = (((((self.emulator().read_u8(echo_address + 3) as i32) << 8)
-                | (self.emulator().read_u8(echo_address + 2) as i32))
-                as i16)
-                & !1) as i32;
+        left_out = dsp_helpers::multiply_volume(left_out, self.vol_left);
+        right_out = dsp_helpers::multiply_volume(right_out, self.vol_right);
 
+        let echo_address = self.echo_start_address.wrapping_add(self.echo_pos as u16);
+        let mut left_echo_in = Dsp::read_echo_sample(memory, echo_address);
+        let mut right_echo_in = Dsp::read_echo_sample(memory, echo_address.wrapping_add(2));
+
+        if !self.is_echo_filter_bypassed {
             left_echo_in = dsp_helpers::clamp(self.left_filter.next(left_echo_in));
             right_echo_in = dsp_helpers::clamp(self.right_filter.next(right_echo_in));
+        }
 
-            let left_out = dsp_helpers::clamp(
-                left_out + dsp_helpers::multiply_volume(left_echo_in, self.echo_vol_left),
-            ) as i16;
-            let right_out = dsp_helpers::clamp(
-                right_out + dsp_helpers::multiply_volume(right_echo_in, self.echo_vol_right),
-            ) as i16;
-            self.output_buffer.push_back((left_out, right_out));
-
-            if self.echo_write_enabled {
-                left_echo_out = dsp_helpers::clamp(
-                    left_echo_out
-                        + ((((left_echo_in * ((self.echo_feedback as i8) as i32)) >> 7) as i16)
-                            as i32),
-                ) & !1;
-                right_echo_out = dsp_helpers::clamp(
-                    right_echo_out
-                        + ((((right_echo_in * ((self.echo_feedback as i8) as i32)) >> 7) as i16)
-                            as i32),
-                ) & !1;
-
-                self.emulator()
-                    .write_u8(echo_address + 0, left_echo_out as u8);
-                self.emulator()
-                    .write_u8(echo_address + 1, (left_echo_out >> 8) as u8);
-                self.emulator()
-                    .write_u8(echo_address + 2, right_echo_out as u8);
-                self.emulator()
-                    .write_u8(echo_address + 3, (right_echo_out >> 8) as u8);
-            }
-            if self.echo_pos == 0 {
-                self.echo_length = self.calculate_echo_length();
-            }
-            self.echo_pos += 4;
-            if self.echo_pos >= self.echo_length {
-                self.echo_pos = 0;
-            }
+        let left_wet = dsp_helpers::multiply_volume(left_echo_in, self.echo_vol_left);
+        let right_wet = dsp_helpers::multiply_volume(right_echo_in, self.echo_vol_right);
+        let (left_out, right_out) = match self.output_tap {
+            OutputTap::Mixed => (left_out + left_wet, right_out + right_wet),
+            OutputTap::DryOnly => (left_out, right_out),
+            OutputTap::EchoOnly => (left_wet, right_wet),
+        };
+        let left_out = dsp_helpers::clamp(left_out) as i16;
+        let right_out = dsp_helpers::clamp(right_out) as i16;
+        let output = (
+            self.apply_master_gain(left_out),
+            self.apply_master_gain(right_out),
+        );
 
-            self.counter = (self.counter + 1) % COUNTER_RANGE;
-            self.cycles_since_last_flush -= 64;
+        if self.echo_write_enabled {
+            left_echo_out = dsp_helpers::clamp(
+                left_echo_out
+                    + ((((left_echo_in * ((self.echo_feedback as i8) as i32)) >> 7) as i16) as i32),
+            ) & !1;
+            right_echo_out = dsp_helpers::clamp(
+                right_echo_out
+                    + ((((right_echo_in * ((self.echo_feedback as i8) as i32)) >> 7) as i16)
+                        as i32),
+            ) & !1;
+
+            Dsp::write_echo_sample(memory, echo_address, left_echo_out);
+            Dsp::write_echo_sample(memory, echo_address.wrapping_add(2), right_echo_out);
+        }
+        if self.echo_pos == 0 {
+            // Buffer wrapped; only now does a changed EDL resize it
+            self.echo_length = self.calculate_echo_length();
         }
+        self.echo_pos += 4;
+        if self.echo_pos >= self.echo_length {
+            self.echo_pos = 0;
+        }
+
+        self.counter = (self.counter + 1) % COUNTER_RANGE;
+        output
+    }
+
+    // The DSP talks to ARAM directly, so echo accesses land in RAM even underneath the
+    //  I/O registers and IPL ROM, and wrap around the end of memory.
+    fn read_echo_sample(memory: &DspMemory, address: u16) -> i32 {
+        let low = memory.read_echo(address) as u16;
+        let high = memory.read_echo(address.wrapping_add(1)) as u16;
+        (((low | (high << 8)) as i16) & !1) as i32
+    }
 
-        self.is_flushing = false;
+    fn write_echo_sample(memory: &mut DspMemory, address: u16, value: i32) {
+        memory.write_echo(address, value as u8);
+        memory.write_echo(address.wrapping_add(1), (value >> 8) as u8);
     }
 
     pub fn set_register(&mut self, address: u8, value: u8) {
@@ -285,9 +752,7 @@ impl<'apu> Dsp<'apu> {
             return;
         }
 
-        if !self.is_flushing {
-            self.flush();
-        }
+        self.regs[address as usize] = value;
 
         let voice_index = address >> 4;
         let voice_address = address & 0x0f;
@@ -364,8 +829,10 @@ impl<'apu> Dsp<'apu> {
                 0x5d => {
                     self.source_dir = value;
                 }
+                // ESA takes effect from the very next sample, mid-buffer or not, while a new
+                //  EDL is only picked up once the current buffer wraps (see render_frame).
                 0x6d => {
-                    self.echo_start_address = (value as u16) << 8;
+                    self.echo_start_address = Dsp::calculate_echo_start_address(value);
                 }
                 0x7d => {
                     self.echo_delay = value & 0x0f;
@@ -377,53 +844,101 @@ impl<'apu> Dsp<'apu> {
     }
 
     pub fn get_register(&mut self, address: u8) -> u8 {
-        if !self.is_flushing {
-            self.flush();
+        let address = address & 0x7f;
+        let voice = &self.voices[(address >> 4) as usize];
+        match address & 0x0f {
+            0x08 => voice.envelope.envx,
+            0x09 => voice.outx,
+            _ => self.regs[address as usize],
+        }
+    }
+
+    /// Like `get_register`, but returns `None` for addresses that don't map to a register.
+    pub fn try_get_register(&mut self, address: u8) -> Option<u8> {
+        if Dsp::is_register_mapped(address) {
+            Some(self.get_register(address))
+        } else {
+            None
         }
+    }
 
-        let _ = address;
-        0
+    /// Like `set_register`, but returns `None` and leaves everything untouched for
+    /// addresses that don't map to a register.
+    pub fn try_set_register(&mut self, address: u8, value: u8) -> Option<()> {
+        if Dsp::is_register_mapped(address) {
+            self.set_register(address, value);
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn is_register_mapped(address: u8) -> bool {
+        if address >= REG_LEN as u8 {
+            return false;
+        }
+        match address & 0x0f {
+            0x0a | 0x0b | 0x0e => false,
+            0x0d => address != 0x1d,
+            _ => true,
+        }
     }
 
     pub fn read_counter(&self, rate: i32) -> bool {
-        ((self.counter + COUNTER_OFFSETS[rate as usize]) % COUNTER_RATES[rate as usize]) != 0
+        Dsp::read_counter_at(self.counter, rate)
     }
 
-    pub fn read_source_dir_start_address(&self, index: i32) -> u32 {
-        self.read_source_dir_address(index, 0)
+    pub(crate) fn read_counter_at(counter: i32, rate: i32) -> bool {
+        ((counter + COUNTER_OFFSETS[rate as usize]) % COUNTER_RATES[rate as usize]) != 0
     }
 
-    pub fn read_source_dir_loop_address(&self, index: i32) -> u32 {
-        self.read_source_dir_address(index, 2)
+    /// The address of the sample directory, as set by DIR.
+    pub fn sample_directory_base(&self) -> u16 {
+        (self.source_dir as u16) << 8
     }
 
-    fn read_source_dir_address(&self, index: i32, offset: i32) -> u32 {
-        let dir_address = (self.source_dir as i32) * 0x100;
-        let entry_address = dir_address + index * 4;
-        let mut ret = self
-            .emulator()
-            .read_u8((entry_address as u32) + (offset as u32)) as u32;
-        ret |= (self
-            .emulator()
-            .read_u8((entry_address as u32) + (offset as u32) + 1) as u32)
-            << 8;
-        ret
+    /// Looks up the start and loop addresses for a source number in the sample directory
+    /// held in `ram`.
+    pub fn sample_start_loop(&self, ram: &[u8], sample_index: u8) -> (u16, u16) {
+        Dsp::read_directory_entry(ram, self.sample_directory_base(), sample_index)
+    }
+
+    pub(crate) fn read_directory_entry(
+        ram: &[u8],
+        directory_base: u16,
+        sample_index: u8,
+    ) -> (u16, u16) {
+        let entry_address = directory_base.wrapping_add((sample_index as u16) * 4);
+        let read_u16 = |address: u16| {
+            (ram[address as usize] as u16) | ((ram[address.wrapping_add(1) as usize] as u16) << 8)
+        };
+        (
+            read_u16(entry_address),
+            read_u16(entry_address.wrapping_add(2)),
+        )
     }
 
+    // Key on/off writes are latched and picked up at the start of the next sample, so a
+    //  voice keyed on mid-batch starts sounding on exactly the following frame.
     fn set_kon(&mut self, voice_mask: u8) {
-        for i in 0..NUM_VOICES {
-            if ((voice_mask as usize) & (1 << i)) != 0 {
-                self.voices[i].key_on();
-            }
-        }
+        self.kon_pending |= voice_mask;
     }
 
     fn set_kof(&mut self, voice_mask: u8) {
+        self.kof_pending |= voice_mask;
+    }
+
+    fn apply_pending_keys(&mut self, ram: &[u8; RAM_LEN], directory_base: u16) {
         for i in 0..NUM_VOICES {
-            if ((voice_mask as usize) & (1 << i)) != 0 {
+            if ((self.kon_pending as usize) & (1 << i)) != 0 {
+                self.voices[i].key_on(ram, directory_base);
+            }
+            if ((self.kof_pending as usize) & (1 << i)) != 0 {
                 self.voices[i].key_off();
             }
         }
+        self.kon_pending = 0;
+        self.kof_pending = 0;
     }
 
     fn set_flg(&mut self, value: u8) {
@@ -449,3 +964,587 @@ impl<'apu> Dsp<'apu> {
         }
     }
 }
+
+impl Default for Dsp {
+    fn default() -> Dsp {
+        Dsp::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::super::super::apu::Apu;
+    use super::*;
+
+    // Every voice is set up to play a loud looping tone from $0300, but nothing is keyed
+    //  on yet. Echo is left silent.
+    pub(crate) fn tone_apu() -> Apu {
+        let mut apu = Apu::new();
+        // Source 0 starts and loops at $0300
+        for (i, &value) in [0x00, 0x03, 0x00, 0x03].iter().enumerate() {
+            apu.write_ram(0x0200 + i as u16, value);
+        }
+        // A single looping BRR block swinging between the extremes
+        apu.write_ram(0x0300, 0xc3);
+        for i in 1..9 {
+            apu.write_ram(0x0300 + i, 0x78);
+        }
+
+        for (address, value) in [(0x0c, 0x7f), (0x1c, 0x7f), (0x2c, 0), (0x3c, 0)] {
+            apu.dsp.set_register(address, value);
+        }
+        apu.dsp.set_register(0x5d, 0x02);
+        apu.dsp.set_register(0x6c, 0x20);
+        for voice in 0..NUM_VOICES as u8 {
+            let base = voice << 4;
+            for (address, value) in [(0x00, 0x7f), (0x01, 0x7f), (0x03, 0x10), (0x05, 0x8f)] {
+                apu.dsp.set_register(base | address, value);
+            }
+        }
+        apu
+    }
+
+    // Steps the DSP for `count` frames and reports whether they were all silent
+    pub(crate) fn is_silent(apu: &mut Apu, count: usize) -> bool {
+        (0..count).all(|_| apu.step_dsp() == (0, 0))
+    }
+
+    #[test]
+    fn muted_voices_are_silent() {
+        let mut apu = tone_apu();
+        apu.dsp.key_on(0xff);
+        assert!(!is_silent(&mut apu, 64));
+
+        let mut apu = tone_apu();
+        for voice in 0..NUM_VOICES {
+            apu.dsp.set_voice_muted(voice, true);
+        }
+        apu.dsp.key_on(0xff);
+        assert!(is_silent(&mut apu, 64));
+
+        // The driver keying voices on again doesn't bring them back
+        apu.write_u8(0xf2, 0x4c);
+        apu.write_u8(0xf3, 0xff);
+        assert!(is_silent(&mut apu, 64));
+        assert!(apu.dsp.is_voice_muted(7));
+
+        apu.dsp.set_voice_muted(3, false);
+        assert!(!is_silent(&mut apu, 64));
+    }
+
+    #[test]
+    fn key_on_starts_on_the_next_sample() {
+        let mut apu = Apu::new();
+        apu.dsp.set_register(0x05, 0x8f);
+
+        apu.dsp.key_on(0x01);
+        assert_eq!(apu.dsp.get_register(0x4c), 0);
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Release);
+
+        apu.step_dsp();
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Attack);
+        assert!(apu.dsp.voice_envelope(0).level > 0);
+        assert_eq!(apu.dsp.voice_envelope(1).phase, EnvelopePhase::Release);
+
+        apu.dsp.key_off(0x01);
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Attack);
+        apu.step_dsp();
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Release);
+    }
+
+    #[test]
+    fn key_writes_apply_on_the_next_frame() {
+        let mut apu = tone_apu();
+        assert_eq!(apu.step_dsp(), (0, 0));
+
+        apu.write_u8(0xf2, 0x4c);
+        apu.write_u8(0xf3, 0x01);
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Release);
+        apu.step_dsp();
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Attack);
+        assert_ne!(apu.dsp.get_register(0x08), 0);
+        // The interpolator starts from an empty history, so the tone itself takes a
+        //  couple more frames to come through
+        assert!(!is_silent(&mut apu, 4));
+
+        apu.write_u8(0xf2, 0x5c);
+        apu.write_u8(0xf3, 0x01);
+        assert_ne!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Release);
+        apu.step_dsp();
+        assert_eq!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Release);
+    }
+
+    #[test]
+    fn pans_voices() {
+        let mut apu = tone_apu();
+        assert_eq!(apu.dsp.voice_pan(0), 0.0);
+
+        apu.dsp.set_voice_volume(0, 40, -40);
+        apu.dsp.set_voice_pan(0, -0.5);
+        assert_eq!(apu.dsp.get_voice_volume(0), (60, -20));
+        assert_eq!(apu.dsp.voice_pan(0), -0.5);
+        apu.dsp.set_voice_pan(0, 0.0);
+        assert_eq!(apu.dsp.get_voice_volume(0), (40, -40));
+        assert_eq!(apu.dsp.voice_pan(0), 0.0);
+
+        // Out of range pans are clamped to the edges
+        apu.dsp.set_voice_pan(0, -2.0);
+        assert_eq!(apu.dsp.get_voice_volume(0), (80, 0));
+        assert_eq!(apu.dsp.voice_pan(0), -1.0);
+
+        apu.dsp.key_on(0x01);
+        let frames: Vec<_> = (0..64).map(|_| apu.step_dsp()).collect();
+        assert!(frames.iter().any(|&(left, _)| left != 0));
+        assert!(frames.iter().all(|&(_, right)| right == 0));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn register_file_matches_the_spc() {
+        let mut spc = Apu::new().to_spc();
+        for (i, reg) in spc.regs.iter_mut().enumerate() {
+            // ENVX and OUTX come from the voices, which haven't played anything yet
+            *reg = match i & 0x0f {
+                0x08 | 0x09 => 0,
+                _ => (i as u8).wrapping_mul(7) ^ 0x5a,
+            };
+        }
+
+        let apu = Apu::from_spc(&spc);
+        assert_eq!(apu.dsp.register_file(), spc.regs);
+    }
+
+    #[test]
+    fn echo_delay_changes_at_the_buffer_wrap() {
+        let mut apu = tone_apu();
+        apu.dsp.set_echo_delay(1);
+        apu.step_dsp();
+        assert_eq!(apu.dsp.echo_length, 0x800);
+
+        // Partway through the 512 frames the buffer takes, the old length still stands...
+        for _ in 0..100 {
+            apu.step_dsp();
+        }
+        apu.dsp.set_echo_delay(3);
+        for _ in 101..512 {
+            apu.step_dsp();
+            assert_eq!(apu.dsp.echo_length, 0x800);
+        }
+        assert_eq!(apu.dsp.echo_pos, 0);
+
+        // ...and the new one takes over as it starts again
+        apu.step_dsp();
+        assert_eq!(apu.dsp.echo_length, 0x1800);
+        assert_eq!(apu.dsp.echo_pos, 4);
+    }
+
+    #[test]
+    fn captured_voices_add_up_to_the_dry_mix() {
+        let mut apu = tone_apu();
+        // Quiet enough that the mix never clamps
+        for voice in 0..NUM_VOICES {
+            apu.dsp.set_voice_volume(voice, 0x10, -0x08);
+        }
+        apu.dsp.key_on(0xff);
+        apu.step_dsp();
+        for voice in 0..NUM_VOICES {
+            assert!(!apu.dsp.is_voice_captured(voice));
+            assert!(apu.dsp.take_voice_samples(voice).is_empty());
+            apu.dsp.set_voice_capture(voice, true);
+        }
+
+        let mixed: Vec<_> = (0..256).map(|_| apu.step_dsp()).collect();
+        let captured: Vec<_> = (0..NUM_VOICES)
+            .map(|voice| apu.dsp.take_voice_samples(voice))
+            .collect();
+        assert!(mixed.iter().any(|&frame| frame != (0, 0)));
+        for (i, &(left, right)) in mixed.iter().enumerate() {
+            let left_sum: i32 = captured.iter().map(|frames| frames[i].0 as i32).sum();
+            let right_sum: i32 = captured.iter().map(|frames| frames[i].1 as i32).sum();
+            // Each voice is scaled by MVOL on its own, so they can round differently
+            assert!((left_sum - left as i32).abs() <= NUM_VOICES as i32);
+            assert!((right_sum - right as i32).abs() <= NUM_VOICES as i32);
+        }
+
+        apu.dsp.set_voice_capture(0, false);
+        apu.step_dsp();
+        assert!(apu.dsp.take_voice_samples(0).is_empty());
+        assert_eq!(apu.dsp.take_voice_samples(1).len(), 1);
+    }
+
+    #[test]
+    fn echo_fir_coefficients_round_trip() {
+        let mut dsp = Dsp::new();
+        let coefficients = [127, -128, 0, 1, -1, 64, -64, 12];
+        dsp.set_echo_fir_coefficients(coefficients);
+        assert_eq!(dsp.get_echo_fir_coefficients(), coefficients);
+        for (i, &coefficient) in coefficients.iter().enumerate() {
+            assert_eq!(dsp.get_register(((i as u8) << 4) | 0x0f), coefficient as u8);
+        }
+
+        // Writes through the registers show up too
+        dsp.set_register(0x3f, 0x90);
+        assert_eq!(dsp.get_echo_fir_coefficients()[3], -0x70);
+    }
+
+    #[test]
+    fn noise_lfsr_steps_at_the_flg_rate() {
+        let mut apu = Apu::new();
+        apu.dsp.set_register(0x6c, 0x20);
+        assert_eq!(apu.dsp.get_noise_clock(), 0);
+        apu.dsp.set_noise_sample(0x1235);
+        assert_eq!(apu.dsp.noise * 2, 0x1234);
+        // Rate 0 never clocks it
+        for _ in 0..100 {
+            apu.step_dsp();
+        }
+        assert_eq!(apu.dsp.noise * 2, 0x1234);
+
+        // At the fastest rate it steps every sample, going through all 32767 non-zero
+        //  states before coming back round
+        apu.dsp.set_register(0x6c, 0x3f);
+        assert_eq!(apu.dsp.get_noise_clock(), 0x1f);
+        apu.dsp.set_noise_seed(0x4000);
+        apu.step_dsp();
+        assert_eq!(apu.dsp.noise, 0x2000);
+        for _ in 1..0x7fff {
+            assert_ne!(apu.dsp.noise, 0x4000);
+            apu.step_dsp();
+        }
+        assert_eq!(apu.dsp.noise, 0x4000);
+
+        // Rate 0x10 steps once every 64 samples
+        apu.dsp.set_register(0x6c, 0x30);
+        apu.dsp.set_noise_seed(0x4000);
+        let mut steps = 0;
+        for _ in 0..640 {
+            let noise = apu.dsp.noise;
+            apu.step_dsp();
+            if apu.dsp.noise != noise {
+                steps += 1;
+            }
+        }
+        assert_eq!(steps, 10);
+    }
+
+    #[test]
+    fn resampling_modes_differ_but_stay_in_range() {
+        let render = |mode| {
+            let mut apu = tone_apu();
+            apu.dsp.set_resampling_mode(mode);
+            apu.dsp.set_voice_pitch(0, 0x0b00);
+            apu.dsp.key_on(0x01);
+            (0..256).map(|_| apu.step_dsp().0).collect::<Vec<i16>>()
+        };
+        let peak = |frames: &[i16]| frames.iter().map(|&x| (x as i32).abs()).max().unwrap();
+
+        let gaussian = render(ResamplingMode::Gaussian);
+        let linear = render(ResamplingMode::Linear);
+        let none = render(ResamplingMode::None);
+        assert!(gaussian != linear && linear != none && none != gaussian);
+        for frames in [&gaussian, &linear, &none] {
+            assert!(peak(frames) > peak(&gaussian) / 2);
+            assert!(peak(frames) < peak(&gaussian) * 2);
+        }
+    }
+
+    #[test]
+    fn master_gain_scales_the_output() {
+        let render = |gain| {
+            let mut apu = tone_apu();
+            apu.dsp.set_master_gain(gain);
+            apu.dsp.key_on(0xff);
+            (0..256).map(|_| apu.step_dsp()).collect::<Vec<_>>()
+        };
+
+        let full = render(1.0);
+        let half = render(0.5);
+        assert!(full.iter().any(|&frame| frame != (0, 0)));
+        for (&(left, right), &(half_left, half_right)) in full.iter().zip(half.iter()) {
+            assert_eq!(half_left, left / 2);
+            assert_eq!(half_right, right / 2);
+        }
+
+        // Boosting clamps rather than wrapping round
+        let loud = render(100.0);
+        assert!(loud.iter().any(|&(left, _)| left.unsigned_abs() >= 0x7fff));
+        for (&(left, _), &(loud_left, _)) in full.iter().zip(loud.iter()) {
+            assert_eq!(left.signum(), loud_left.signum());
+        }
+    }
+
+    #[test]
+    fn pitch_modulation_follows_the_previous_voice() {
+        // Voice 1's share of the mix, with `voices` keyed on
+        let render = |pmon, voices| {
+            let mut apu = tone_apu();
+            apu.dsp.set_pitch_modulation_enabled(pmon);
+            apu.dsp.set_voice_capture(1, true);
+            apu.dsp.key_on(voices);
+            for _ in 0..256 {
+                apu.step_dsp();
+            }
+            apu.dsp.take_voice_samples(1)
+        };
+
+        let mut apu = tone_apu();
+        apu.dsp.set_pitch_modulation_enabled(0x03);
+        // Voice 0 has nothing before it to follow, though PMON itself keeps the bit
+        assert_eq!(apu.dsp.get_pitch_modulation_enabled(), 0x02);
+        assert_eq!(apu.dsp.get_register(0x2d), 0x03);
+
+        let unmodulated = render(0x00, 0x03);
+        assert!(render(0x02, 0x03) != unmodulated);
+        // With voice 0 silent there's nothing to modulate by
+        assert!(render(0x02, 0x02) == unmodulated);
+    }
+
+    #[test]
+    fn takes_no_more_samples_than_are_buffered() {
+        let mut apu = tone_apu();
+        apu.dsp.key_on(0xff);
+        apu.run(64 * 10);
+        let available = apu.dsp.available_samples();
+        assert!(available >= 9);
+
+        let mut out = vec![(0, 0); 100];
+        assert_eq!(apu.dsp.take_samples(&mut out[..2]), 2);
+        assert_eq!(apu.dsp.available_samples(), available - 2);
+        assert_eq!(apu.dsp.take_samples(&mut out[2..]), available - 2);
+        assert_eq!(apu.dsp.available_samples(), 0);
+        assert_eq!(apu.dsp.take_samples(&mut out), 0);
+
+        // They came out in order, matching what stepping the DSP gives
+        let mut control = tone_apu();
+        control.dsp.key_on(0xff);
+        for &frame in out[..available].iter() {
+            assert_eq!(frame, control.step_dsp());
+        }
+    }
+
+    #[test]
+    fn envelope_climbs_through_the_attack() {
+        let mut apu = tone_apu();
+        // Attack rate 29, which steps every third sample
+        apu.dsp.set_register(0x05, 0x8e);
+        apu.dsp.key_on(0x01);
+
+        let mut last_level = 0;
+        for _ in 0..10 {
+            apu.run(64 * 8);
+            let state = apu.dsp.voice_envelope(0);
+            assert_eq!(apu.dsp.voice_envelope(0), state);
+            assert_eq!(state.phase, EnvelopePhase::Attack);
+            assert!(state.level > last_level);
+            last_level = state.level;
+        }
+
+        apu.run(64 * 128);
+        assert_ne!(apu.dsp.voice_envelope(0).phase, EnvelopePhase::Attack);
+        assert_eq!(apu.dsp.voice_envelope(1).phase, EnvelopePhase::Release);
+    }
+
+    #[test]
+    fn negative_volume_inverts_the_voice() {
+        let mut positive = tone_apu();
+        let mut negative = tone_apu();
+        negative.dsp.set_voice_volume(0, -0x7f, 0x7f);
+        assert_eq!(negative.dsp.get_voice_volume(0), (-0x7f, 0x7f));
+        assert_eq!(negative.dsp.get_register(0x00), 0x81);
+        positive.dsp.key_on(0x01);
+        negative.dsp.key_on(0x01);
+
+        let mut heard = false;
+        for _ in 0..1000 {
+            let (left, right) = positive.step_dsp();
+            let (inverted_left, same_right) = negative.step_dsp();
+            // Within rounding, as the volume shifts floor rather than truncate
+            assert!((left as i32 + inverted_left as i32).abs() <= 2);
+            assert_eq!(same_right, right);
+            heard |= left.abs() > 0x1000;
+        }
+        assert!(heard);
+    }
+
+    #[test]
+    fn output_taps_split_the_mix_without_echo() {
+        let mut taps = [OutputTap::Mixed, OutputTap::DryOnly, OutputTap::EchoOnly].map(|tap| {
+            let mut apu = tone_apu();
+            apu.dsp.set_output_tap(tap);
+            apu.dsp.key_on(0x01);
+            apu
+        });
+        let [mixed, dry, echo] = &mut taps;
+        assert_eq!(echo.dsp.output_tap(), OutputTap::EchoOnly);
+
+        let mut heard = false;
+        for _ in 0..1000 {
+            let frame = mixed.step_dsp();
+            assert_eq!(dry.step_dsp(), frame);
+            assert_eq!(echo.step_dsp(), (0, 0));
+            heard |= frame != (0, 0);
+        }
+        assert!(heard);
+    }
+
+    #[test]
+    fn noise_seed_makes_noise_reproducible() {
+        let noise_apu = |seed| {
+            let mut apu = tone_apu();
+            // Noise on voice 0 at the fastest rate
+            apu.dsp.set_register(0x3d, 0x01);
+            apu.dsp.set_register(0x6c, 0x3f);
+            apu.dsp.set_noise_seed(seed);
+            apu.dsp.key_on(0x01);
+            apu
+        };
+        let mut first = noise_apu(0x1234);
+        let mut second = noise_apu(0x1234);
+        let mut other = noise_apu(0x0001);
+
+        let mut differs = false;
+        for _ in 0..1000 {
+            let frame = first.step_dsp();
+            assert_eq!(second.step_dsp(), frame);
+            differs |= other.step_dsp() != frame;
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn unmapped_registers_are_refused() {
+        let mut apu = tone_apu();
+        let state = apu.save_state();
+        for address in [0x0a, 0x1d, 0x4e, 0x7b, 0x80, 0xff] {
+            assert_eq!(apu.dsp.try_get_register(address), None);
+            assert_eq!(apu.dsp.try_set_register(address, 0x55), None);
+        }
+        assert!(apu.save_state() == state);
+
+        // $4f is FIR coefficient 4, not a gap
+        assert_eq!(apu.dsp.try_set_register(0x4f, 0x40), Some(()));
+        assert_eq!(apu.dsp.try_get_register(0x4f), Some(0x40));
+        assert_eq!(apu.dsp.try_get_register(0x0c), Some(0x7f));
+    }
+
+    #[test]
+    fn looks_up_the_sample_directory() {
+        let mut apu = tone_apu();
+        apu.dsp.set_register(0x5d, 0x12);
+        assert_eq!(apu.dsp.sample_directory_base(), 0x1200);
+
+        let mut ram = vec![0; RAM_LEN];
+        // Source 3 at $120c, starting at $4000 and looping at $4123
+        ram[0x120c..0x1210].copy_from_slice(&[0x00, 0x40, 0x23, 0x41]);
+        assert_eq!(apu.dsp.sample_start_loop(&ram, 3), (0x4000, 0x4123));
+        assert_eq!(apu.dsp.sample_start_loop(&ram, 2), (0, 0));
+
+        // The last source in a directory at $ff00 runs off the end of memory
+        apu.dsp.set_register(0x5d, 0xff);
+        ram[0xfffc..].copy_from_slice(&[0x34, 0x12, 0x78, 0x56]);
+        assert_eq!(apu.dsp.sample_start_loop(&ram, 0x3f), (0x1234, 0x5678));
+        ram[0] = 0xbc;
+        assert_eq!(apu.dsp.sample_start_loop(&ram, 0x40), (0x00bc, 0));
+    }
+
+    #[test]
+    fn envx_rises_after_key_on() {
+        let mut apu = tone_apu();
+        apu.dsp.set_register(0x05, 0x8e);
+        apu.dsp.key_on(0x01);
+
+        let mut last_envx = 0;
+        for _ in 0..10 {
+            apu.run(64 * 8);
+            let envx = apu.dsp.get_register(0x08);
+            assert!(envx > last_envx);
+            // ENVX is the top 7 of the envelope's 11 bits
+            assert_eq!(envx as u16, apu.dsp.voice_envelope(0).level >> 4);
+            last_envx = envx;
+        }
+        // OUTX is the top byte of the last enveloped sample
+        assert_ne!(apu.dsp.get_register(0x09), 0);
+        assert_eq!(apu.dsp.get_register(0x18), 0);
+        assert_eq!(apu.dsp.get_register(0x19), 0);
+    }
+
+    #[test]
+    fn echo_write_disable_stops_the_feedback() {
+        for &flg in [0x20, 0x00].iter() {
+            let mut apu = tone_apu();
+            // A 2kb echo buffer at $8000, fed by voice 0 alone, with full feedback
+            for (address, value) in [(0x6d, 0x80), (0x7d, 0x01), (0x2c, 0x7f), (0x3c, 0x7f)] {
+                apu.dsp.set_register(address, value);
+            }
+            apu.dsp.set_echo_enabled_voices(0x01);
+            apu.dsp.set_echo_feedback(0x7f);
+            apu.dsp.set_register(0x6c, flg);
+            assert_eq!(apu.dsp.get_echo_enabled_voices(), 0x01);
+            apu.dsp.set_output_tap(OutputTap::EchoOnly);
+            apu.dsp.key_on(0x01);
+
+            let is_echo_silent = is_silent(&mut apu, 4000);
+            let is_buffer_clear = (0x8000..0x8800).all(|address| apu.read_ram(address) == 0);
+            assert_eq!(is_echo_silent, flg == 0x20);
+            assert_eq!(is_buffer_clear, flg == 0x20);
+        }
+    }
+
+    #[test]
+    fn echo_delay_and_feedback_round_trip() {
+        let mut apu = tone_apu();
+        apu.dsp.set_echo_delay(4);
+        assert_eq!(apu.dsp.get_echo_delay(), 4);
+        assert_eq!(apu.dsp.echo_buffer_size_bytes(), 8192);
+
+        // EDL only has four bits, and even 0 leaves a single frame
+        apu.dsp.set_echo_delay(0x1f);
+        assert_eq!(apu.dsp.echo_buffer_size_bytes(), 0xf * 2048);
+        apu.dsp.set_echo_delay(0);
+        assert_eq!(apu.dsp.echo_buffer_size_bytes(), 4);
+
+        apu.dsp.set_echo_feedback(-0x40);
+        assert_eq!(apu.dsp.get_echo_feedback(), -0x40);
+        assert_eq!(apu.dsp.get_register(0x0d), 0xc0);
+    }
+
+    #[test]
+    fn voice_pitch_spans_two_registers() {
+        let mut apu = tone_apu();
+        apu.dsp.set_voice_pitch(3, 0x1234);
+        assert_eq!(apu.dsp.get_register(0x32), 0x34);
+        assert_eq!(apu.dsp.get_register(0x33), 0x12);
+        assert_eq!(apu.dsp.get_voice_pitch(3), 0x1234);
+
+        // Only 14 bits are kept, whichever way it's written
+        apu.dsp.set_voice_pitch(3, 0xd234);
+        assert_eq!(apu.dsp.get_register(0x33), 0x12);
+        apu.dsp.set_register(0x33, 0xff);
+        assert_eq!(apu.dsp.get_voice_pitch(3), 0x3f34);
+        assert_eq!(apu.dsp.get_voice_pitch(2), 0x1000);
+    }
+
+    #[test]
+    fn stepping_follows_the_attack_sample_by_sample() {
+        let mut apu = tone_apu();
+        apu.dsp.set_register(0x05, 0x8e);
+        apu.dsp.key_on(0x01);
+
+        let levels: Vec<u16> = (0..64)
+            .map(|_| {
+                apu.step_dsp();
+                apu.dsp.voice_envelope(0).level
+            })
+            .collect();
+        // Rate 29 adds 0x20 on every third sample
+        for pair in levels.windows(2) {
+            assert!(pair[1] == pair[0] || pair[1] == pair[0] + 0x20);
+        }
+        let num_steps = levels.windows(2).filter(|pair| pair[1] != pair[0]).count();
+        assert!((19..=21).contains(&num_steps));
+
+        // Only the DSP moved
+        assert_eq!(apu.smp.cycle_count(), 0);
+        assert_eq!(apu.dsp.output_buffer.len(), 0);
+    }
+}
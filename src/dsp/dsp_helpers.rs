@@ -1,12 +1,8 @@
+// Volume registers are signed; negative values phase-invert
 pub fn multiply_volume(value: i32, volume: u8) -> i32 {
-    (value * (volume as i32)) >> 7
+    (value * ((volume as i8) as i32)) >> 7
 }
 
 pub fn clamp(value: i32) -> i32 {
-    if value < -32768 {
-        return -32768;
-    } else if value > 32767 {
-        return 32767;
-    }
-    return value;
+    value.clamp(-32768, 32767)
 }
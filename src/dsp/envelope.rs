@@ -1,6 +1,6 @@
-use std::rc::Weak;
-
-use super::dsp::Dsp;
+use super::super::apu::LoadStateError;
+use super::super::state::{StateReader, StateWriter};
+use super::dsp::{Dsp, EnvelopePhase};
 
 enum Mode {
     Attack,
@@ -9,9 +9,7 @@ enum Mode {
     Release,
 }
 
-pub struct Envelope<'apu> {
-    dsp: Weak<Dsp<'apu>>,
-
+pub struct Envelope {
     pub adsr0: u8,
     pub adsr1: u8,
     pub gain: u8,
@@ -22,11 +20,9 @@ pub struct Envelope<'apu> {
     hidden_level: i32,
 }
 
-impl Envelope<'_> {
-    pub fn new(dsp: Weak<Dsp>) -> Envelope {
+impl Envelope {
+    pub fn new() -> Envelope {
         Envelope {
-            dsp: dsp,
-
             adsr0: 0,
             adsr1: 0,
             gain: 0,
@@ -38,6 +34,17 @@ impl Envelope<'_> {
         }
     }
 
+    pub fn reset(&mut self) {
+        self.adsr0 = 0;
+        self.adsr1 = 0;
+        self.gain = 0;
+        self.envx = 0;
+
+        self.mode = Mode::Release;
+        self.level = 0;
+        self.hidden_level = 0;
+    }
+
     pub fn key_on(&mut self) {
         self.mode = Mode::Attack;
         self.level = 0;
@@ -48,7 +55,56 @@ impl Envelope<'_> {
         self.mode = Mode::Release;
     }
 
-    pub fn tick(&mut self) {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.adsr0);
+        writer.write_u8(self.adsr1);
+        writer.write_u8(self.gain);
+        writer.write_u8(self.envx);
+        writer.write_u8(match self.mode {
+            Mode::Attack => 0,
+            Mode::Decay => 1,
+            Mode::Sustain => 2,
+            Mode::Release => 3,
+        });
+        writer.write_i32(self.level);
+        writer.write_i32(self.hidden_level);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        self.adsr0 = reader.read_u8()?;
+        self.adsr1 = reader.read_u8()?;
+        self.gain = reader.read_u8()?;
+        self.envx = reader.read_u8()?;
+        self.mode = match reader.read_u8()? {
+            0 => Mode::Attack,
+            1 => Mode::Decay,
+            2 => Mode::Sustain,
+            3 => Mode::Release,
+            _ => return Err(LoadStateError::Invalid),
+        };
+        self.level = reader.read_i32()?;
+        self.hidden_level = reader.read_i32()?;
+        // `hidden_level` is the unclamped value from the last tick, so it can stray a little
+        //  either side of the level's 11 bits.
+        if !(0..=0x07ff).contains(&self.level) || !(-0x0800..0x1000).contains(&self.hidden_level) {
+            return Err(LoadStateError::Invalid);
+        }
+        Ok(())
+    }
+
+    pub fn phase(&self) -> EnvelopePhase {
+        match self.mode {
+            Mode::Release => EnvelopePhase::Release,
+            _ if (self.adsr0 & 0x80) == 0 => EnvelopePhase::Gain,
+            Mode::Attack => EnvelopePhase::Attack,
+            Mode::Decay => EnvelopePhase::Decay,
+            Mode::Sustain => EnvelopePhase::Sustain,
+        }
+    }
+
+    /// Advances the envelope by one sample. `counter` is the DSP's global rate counter,
+    /// which decides whether this sample is one the current rate steps on.
+    pub fn tick(&mut self, counter: i32) {
         let mut env = self.level;
         match self.mode {
             Mode::Release => {
@@ -123,7 +179,7 @@ impl Envelope<'_> {
                     }
                 }
 
-                if self.dsp.upgrade().unwrap().read_counter(rate) {
+                if Dsp::read_counter_at(counter, rate) {
                     return;
                 }
                 self.level = env;
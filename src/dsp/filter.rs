@@ -1,3 +1,6 @@
+use super::super::apu::LoadStateError;
+use super::super::state::{StateReader, StateWriter};
+
 const NUM_TAPS: usize = 8;
 
 pub struct Filter {
@@ -17,6 +20,23 @@ impl Filter {
         }
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.coefficients);
+        for value in self.buffer.iter() {
+            writer.write_i32(*value);
+        }
+        writer.write_i32(self.buffer_pos);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        reader.read_bytes(&mut self.coefficients)?;
+        for value in self.buffer.iter_mut() {
+            *value = reader.read_i32()?;
+        }
+        self.buffer_pos = reader.read_i32()?.rem_euclid(NUM_TAPS as i32);
+        Ok(())
+    }
+
     pub fn next(&mut self, value: i32) -> i32 {
         self.buffer[self.buffer_pos as usize] = value;
 
@@ -1,4 +1,6 @@
+pub mod brr;
 mod brr_block_decoder;
+#[allow(clippy::module_inception)]
 pub mod dsp;
 mod dsp_helpers;
 mod envelope;
@@ -1,4 +1,8 @@
-use super::super::apu::Apu;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::super::apu::{LoadStateError, RAM_LEN};
+use super::super::state::{StateReader, StateWriter};
 use super::brr_block_decoder::BrrBlockDecoder;
 use super::dsp::Dsp;
 use super::dsp_helpers;
@@ -9,27 +13,18 @@ const RESAMPLE_BUFFER_LEN: usize = 4;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ResamplingMode {
+    None,
     Linear,
     Gaussian,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct VoiceOutput {
     pub left_out: i32,
     pub right_out: i32,
     pub last_voice_out: i32,
 }
 
-impl VoiceOutput {
-    pub fn default() -> VoiceOutput {
-        VoiceOutput {
-            left_out: 0,
-            right_out: 0,
-            last_voice_out: 0,
-        }
-    }
-}
-
 pub const VOICE_BUFFER_LEN: usize = 128;
 
 #[derive(Debug, Clone)]
@@ -52,11 +47,13 @@ impl VoiceBuffer {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Voice {
-    dsp: Weak<Dsp>,
-    emulator: Weak<Apu>,
+impl Default for VoiceBuffer {
+    fn default() -> VoiceBuffer {
+        VoiceBuffer::new()
+    }
+}
 
+pub struct Voice {
     pub envelope: Envelope,
 
     pub vol_left: u8,
@@ -69,10 +66,10 @@ pub struct Voice {
     pub noise_on: bool,
     pub echo_on: bool,
 
-    sample_start_address: u32,
-    loop_start_address: u32,
+    sample_start_address: u16,
+    loop_start_address: u16,
     brr_block_decoder: BrrBlockDecoder,
-    sample_address: u32,
+    sample_address: u16,
     sample_pos: i32,
 
     pub resampling_mode: ResamplingMode,
@@ -82,15 +79,14 @@ pub struct Voice {
     pub output_buffer: VoiceBuffer,
     pub is_muted: bool,
     pub is_solod: bool,
+    /// Set while capture is enabled; `Dsp` appends this voice's share of the mix to it.
+    pub capture_buffer: Option<Vec<(i16, i16)>>,
 }
 
 impl Voice {
-    pub fn new(dsp: *mut Dsp, emulator: *mut Apu, resampling_mode: ResamplingMode) -> Voice {
+    pub fn new(resampling_mode: ResamplingMode) -> Voice {
         Voice {
-            dsp: dsp,
-            emulator: emulator,
-
-            envelope: Envelope::new(dsp),
+            envelope: Envelope::new(),
 
             vol_left: 0,
             vol_right: 0,
@@ -108,28 +104,105 @@ impl Voice {
             sample_address: 0,
             sample_pos: 0,
 
-            resampling_mode: resampling_mode,
+            resampling_mode,
             resample_buffer: [0; RESAMPLE_BUFFER_LEN],
             resample_buffer_pos: 0,
 
             output_buffer: VoiceBuffer::new(),
             is_muted: false,
             is_solod: false,
+            capture_buffer: None,
         }
     }
 
-    #[inline]
-    fn dsp(&self) -> &mut Dsp {
-        unsafe { &mut (*self.dsp) }
+    pub fn reset(&mut self) {
+        self.envelope.reset();
+
+        self.vol_left = 0;
+        self.vol_right = 0;
+        self.pitch_low = 0;
+        self.pitch_high = 0x10;
+        self.source = 0;
+        self.outx = 0;
+        self.pitch_mod = false;
+        self.noise_on = false;
+        self.echo_on = false;
+
+        self.sample_start_address = 0;
+        self.loop_start_address = 0;
+        self.brr_block_decoder = BrrBlockDecoder::new();
+        self.sample_address = 0;
+        self.sample_pos = 0;
+
+        self.resample_buffer = [0; RESAMPLE_BUFFER_LEN];
+        self.resample_buffer_pos = 0;
+
+        self.output_buffer = VoiceBuffer::new();
     }
 
-    #[inline]
-    fn emulator(&self) -> &mut Apu {
-        unsafe { &mut (*self.emulator) }
+    // Only emulation state is saved; muting, soloing and the resampling mode are left
+    //  as the caller configured them.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.envelope.save_state(writer);
+
+        writer.write_u8(self.vol_left);
+        writer.write_u8(self.vol_right);
+        writer.write_u8(self.pitch_low);
+        writer.write_u8(self.pitch_high);
+        writer.write_u8(self.source);
+        writer.write_u8(self.outx);
+        writer.write_bool(self.pitch_mod);
+        writer.write_bool(self.noise_on);
+        writer.write_bool(self.echo_on);
+
+        writer.write_u32(self.sample_start_address as u32);
+        writer.write_u32(self.loop_start_address as u32);
+        self.brr_block_decoder.save_state(writer);
+        writer.write_u32(self.sample_address as u32);
+        writer.write_i32(self.sample_pos);
+
+        for sample in self.resample_buffer.iter() {
+            writer.write_i32(*sample);
+        }
+        writer.write_u8(self.resample_buffer_pos as u8);
     }
 
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        self.envelope.load_state(reader)?;
+
+        self.vol_left = reader.read_u8()?;
+        self.vol_right = reader.read_u8()?;
+        self.pitch_low = reader.read_u8()?;
+        self.set_pitch_high(reader.read_u8()?);
+        self.source = reader.read_u8()?;
+        self.outx = reader.read_u8()?;
+        self.pitch_mod = reader.read_bool()?;
+        self.noise_on = reader.read_bool()?;
+        self.echo_on = reader.read_bool()?;
+
+        self.sample_start_address = reader.read_u32()? as u16;
+        self.loop_start_address = reader.read_u32()? as u16;
+        self.brr_block_decoder.load_state(reader)?;
+        self.sample_address = reader.read_u32()? as u16;
+        self.sample_pos = reader.read_i32()?;
+        if !(0..0x1000).contains(&self.sample_pos) {
+            return Err(LoadStateError::Invalid);
+        }
+
+        for sample in self.resample_buffer.iter_mut() {
+            *sample = reader.read_i32()?;
+        }
+        self.resample_buffer_pos = (reader.read_u8()? as usize) % RESAMPLE_BUFFER_LEN;
+        Ok(())
+    }
+
+    // The DSP reads samples straight out of ARAM, so `ram` is the raw array rather than
+    //  anything going through the SMP's I/O registers.
     pub fn render_sample(
         &mut self,
+        ram: &[u8; RAM_LEN],
+        directory_base: u16,
+        counter: i32,
         last_voice_out: i32,
         noise: i32,
         are_any_voices_solod: bool,
@@ -138,17 +211,13 @@ impl Voice {
         if self.pitch_mod {
             pitch += ((last_voice_out >> 5) * pitch) >> 10;
         }
-        if pitch < 0 {
-            pitch = 0;
-        }
-        if pitch > 0x3fff {
-            pitch = 0x3fff;
-        }
+        pitch = pitch.clamp(0, 0x3fff);
 
         let mut sample = if !self.noise_on {
             let s1 = self.resample_buffer[self.resample_buffer_pos];
             let s2 = self.resample_buffer[(self.resample_buffer_pos + 1) % RESAMPLE_BUFFER_LEN];
             let resampled = match self.resampling_mode {
+                ResamplingMode::None => s2,
                 ResamplingMode::Linear => {
                     let p1 = self.sample_pos;
                     let p2 = 0x1000 - p1;
@@ -174,11 +243,15 @@ impl Voice {
             ((noise * 2) as i16) as i32
         };
 
-        self.envelope.tick();
+        self.envelope.tick(counter);
         let env_level = self.envelope.level;
+        self.envelope.envx = (env_level >> 4) as u8;
 
         sample = ((sample * env_level) >> 11) & !1;
+        self.outx = (sample >> 8) as u8;
 
+        // A one-shot sample is cut off as soon as its END block comes up, rather than after
+        //  playing it, and the voice drops straight to silence in the release phase.
         if self.brr_block_decoder.is_end && !self.brr_block_decoder.is_looping {
             self.envelope.key_off();
             self.envelope.level = 0;
@@ -190,11 +263,14 @@ impl Voice {
             self.read_next_sample();
 
             if self.brr_block_decoder.is_finished() {
-                if self.brr_block_decoder.is_end && self.brr_block_decoder.is_looping {
-                    self.read_entry();
+                // The hardware jumps to the loop address after any END block, LOOP or not.
+                //  Without LOOP the voice is already silent, so this only keeps it from
+                //  wandering off through the rest of RAM.
+                if self.brr_block_decoder.is_end {
+                    self.read_entry(ram, directory_base);
                     self.sample_address = self.loop_start_address;
                 }
-                self.read_next_block();
+                self.read_next_block(ram);
             }
         }
 
@@ -205,10 +281,11 @@ impl Voice {
                 last_voice_out: sample,
             }
         } else {
+            // Muting is purely an output thing; the next voice still sees this one for pitch mod
             VoiceOutput {
                 left_out: 0,
                 right_out: 0,
-                last_voice_out: 0,
+                last_voice_out: sample,
             }
         };
         self.output_buffer.write(ret);
@@ -219,11 +296,11 @@ impl Voice {
         self.pitch_high = value & 0x3f;
     }
 
-    pub fn key_on(&mut self) {
-        self.read_entry();
+    pub fn key_on(&mut self, ram: &[u8; RAM_LEN], directory_base: u16) {
+        self.read_entry(ram, directory_base);
         self.sample_address = self.sample_start_address;
         self.brr_block_decoder.reset(0, 0);
-        self.read_next_block();
+        self.read_next_block(ram);
         self.sample_pos = 0;
         for i in 0..RESAMPLE_BUFFER_LEN {
             self.resample_buffer[i] = 0;
@@ -236,18 +313,19 @@ impl Voice {
         self.envelope.key_off();
     }
 
-    fn read_entry(&mut self) {
-        self.sample_start_address = self.dsp().read_source_dir_start_address(self.source as i32);
-        self.loop_start_address = self.dsp().read_source_dir_loop_address(self.source as i32);
+    fn read_entry(&mut self, ram: &[u8; RAM_LEN], directory_base: u16) {
+        let (start, loop_start) = Dsp::read_directory_entry(ram, directory_base, self.source);
+        self.sample_start_address = start;
+        self.loop_start_address = loop_start;
     }
 
-    fn read_next_block(&mut self) {
+    fn read_next_block(&mut self, ram: &[u8; RAM_LEN]) {
         let mut buf = [0; 9];
         for i in 0..9 {
-            buf[i] = self.emulator().read_u8(self.sample_address + (i as u32));
+            buf[i] = ram[self.sample_address.wrapping_add(i as u16) as usize];
         }
         self.brr_block_decoder.read(&buf);
-        self.sample_address += 9;
+        self.sample_address = self.sample_address.wrapping_add(9);
     }
 
     fn read_next_sample(&mut self) {
@@ -259,3 +337,94 @@ impl Voice {
             self.brr_block_decoder.read_next_sample() as i32;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::super::dsp::EnvelopePhase;
+    use super::*;
+
+    fn save(voice: &Voice) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        voice.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    fn load(state: &[u8]) -> Result<(), LoadStateError> {
+        Voice::new(ResamplingMode::Gaussian).load_state(&mut StateReader::new(state))
+    }
+
+    fn patched(offset: usize, value: i32) -> Vec<u8> {
+        let mut state = save(&Voice::new(ResamplingMode::Gaussian));
+        state[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        state
+    }
+
+    #[test]
+    fn rejects_impossible_state() {
+        assert_eq!(load(&save(&Voice::new(ResamplingMode::Gaussian))), Ok(()));
+
+        // Envelope level and hidden level
+        assert_eq!(load(&patched(5, 0x0800)), Err(LoadStateError::Invalid));
+        assert_eq!(load(&patched(5, -1)), Err(LoadStateError::Invalid));
+        assert_eq!(load(&patched(9, 0x1000)), Err(LoadStateError::Invalid));
+        // BRR decoder sample index
+        assert_eq!(load(&patched(64, 16)), Err(LoadStateError::Invalid));
+        assert_eq!(load(&patched(64, -1)), Err(LoadStateError::Invalid));
+        // Position between samples
+        assert_eq!(load(&patched(76, -100000)), Err(LoadStateError::Invalid));
+        assert_eq!(load(&patched(76, 0x1000)), Err(LoadStateError::Invalid));
+    }
+
+    // Source 0 is a loud block at $0300 followed by one with `end_header` at $0309. Its
+    //  directory entry loops to a quieter END+LOOP block at $0312.
+    fn brr_fixture(end_header: u8) -> Box<[u8; RAM_LEN]> {
+        let mut ram = Box::new([0; RAM_LEN]);
+        ram[0x0200..0x0204].copy_from_slice(&[0x00, 0x03, 0x12, 0x03]);
+        for (address, header, data) in [
+            (0x0300, 0xb0, 0x77),
+            (0x0309, end_header, 0x77),
+            (0x0312, 0xb3, 0x11),
+        ] {
+            ram[address] = header;
+            ram[address + 1..address + 9].fill(data);
+        }
+        ram
+    }
+
+    // Keys on source 0 at 32kHz and renders `count` samples from it
+    fn play(ram: &[u8; RAM_LEN], count: usize) -> (Voice, Vec<i32>) {
+        let mut voice = Voice::new(ResamplingMode::Gaussian);
+        voice.vol_left = 0x7f;
+        voice.set_pitch_high(0x10);
+        voice.envelope.adsr0 = 0x8f;
+        voice.key_on(ram, 0x0200);
+        let output = (0..count as i32)
+            .map(|counter| {
+                voice
+                    .render_sample(ram, 0x0200, counter, 0, 0, false)
+                    .left_out
+            })
+            .collect();
+        (voice, output)
+    }
+
+    #[test]
+    fn end_without_loop_keys_off() {
+        let (voice, output) = play(&brr_fixture(0xb1), 64);
+        // Cut off as soon as the END block comes up, 16 samples in
+        assert!(output[..15].iter().any(|&sample| sample != 0));
+        assert!(output[16..].iter().all(|&sample| sample == 0));
+        assert_eq!(voice.envelope.phase(), EnvelopePhase::Release);
+    }
+
+    #[test]
+    fn end_with_loop_jumps_to_the_loop_address() {
+        let (voice, output) = play(&brr_fixture(0xb3), 64);
+        assert!(output[40..].iter().all(|&sample| sample != 0));
+        assert_ne!(voice.envelope.phase(), EnvelopePhase::Release);
+        // Still going round the block at the loop address, not running on past it
+        assert_eq!(voice.sample_address, 0x031b);
+    }
+}
@@ -0,0 +1,255 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+
+use super::apu::Apu;
+use super::spc::spc::Spc;
+
+/// Opaque handle handed out to C. Tag strings are converted up front so the pointers
+/// returned by the getters stay valid for as long as the handle does.
+pub struct SnesApu {
+    apu: Apu,
+
+    song_title: CString,
+    game_title: CString,
+    artist_name: CString,
+    dumper_name: CString,
+    comments: CString,
+    seconds_to_play_before_fading_out: i32,
+    fade_out_length: i32,
+}
+
+fn to_c_string(value: &str) -> CString {
+    // Anything after an embedded NUL wouldn't be visible from C anyway
+    let value = value.split('\0').next().unwrap_or("");
+    CString::new(value).unwrap_or_default()
+}
+
+/// Loads an SPC file and returns a handle to an APU booted from it, or null if the file
+/// couldn't be read. The handle must be released with `snes_apu_free`.
+///
+/// # Safety
+///
+/// `path` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_load(path: *const c_char) -> *mut SnesApu {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+    let spc = match Spc::load(path) {
+        Ok(spc) => spc,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let mut handle = SnesApu {
+        apu: Apu::from_spc(&spc),
+
+        song_title: CString::default(),
+        game_title: CString::default(),
+        artist_name: CString::default(),
+        dumper_name: CString::default(),
+        comments: CString::default(),
+        seconds_to_play_before_fading_out: 0,
+        fade_out_length: 0,
+    };
+    if let Some(ref id666_tag) = spc.id666_tag {
+        handle.song_title = to_c_string(&id666_tag.song_title);
+        handle.game_title = to_c_string(&id666_tag.game_title);
+        handle.artist_name = to_c_string(&id666_tag.artist_name);
+        handle.dumper_name = to_c_string(&id666_tag.dumper_name);
+        handle.comments = to_c_string(&id666_tag.comments);
+        handle.seconds_to_play_before_fading_out = id666_tag.seconds_to_play_before_fading_out;
+        handle.fade_out_length = id666_tag.fade_out_length;
+    }
+    Box::into_raw(Box::new(handle))
+}
+
+/// Renders `frames` stereo frames into `buffer` as interleaved 16-bit L, R pairs, so
+/// `buffer` must have room for `frames * 2` samples.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`, and `buffer` must be null
+/// or valid for writes of `frames * 2` samples.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_render(handle: *mut SnesApu, buffer: *mut i16, frames: usize) {
+    if handle.is_null() || buffer.is_null() {
+        return;
+    }
+    let buffer = slice::from_raw_parts_mut(buffer, frames * 2);
+    (*handle).apu.render_interleaved(buffer);
+}
+
+/// Releases a handle and everything the getters handed out from it.
+///
+/// # Safety
+///
+/// `handle` must be null or a handle from `snes_apu_load` that hasn't been freed yet.
+/// It mustn't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_free(handle: *mut SnesApu) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+// The tag getters return empty strings when the file had no ID666 tag, and null or 0
+// when passed a null handle. The strings are owned by the handle and freed along with it.
+
+/// The song title from the ID666 tag.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_song_title(handle: *const SnesApu) -> *const c_char {
+    if handle.is_null() {
+        return ptr::null();
+    }
+    (*handle).song_title.as_ptr()
+}
+
+/// The game title from the ID666 tag.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_game_title(handle: *const SnesApu) -> *const c_char {
+    if handle.is_null() {
+        return ptr::null();
+    }
+    (*handle).game_title.as_ptr()
+}
+
+/// The artist from the ID666 tag.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_artist_name(handle: *const SnesApu) -> *const c_char {
+    if handle.is_null() {
+        return ptr::null();
+    }
+    (*handle).artist_name.as_ptr()
+}
+
+/// Who dumped the SPC, from the ID666 tag.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_dumper_name(handle: *const SnesApu) -> *const c_char {
+    if handle.is_null() {
+        return ptr::null();
+    }
+    (*handle).dumper_name.as_ptr()
+}
+
+/// The comments from the ID666 tag.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_comments(handle: *const SnesApu) -> *const c_char {
+    if handle.is_null() {
+        return ptr::null();
+    }
+    (*handle).comments.as_ptr()
+}
+
+/// How many seconds the tag says to play before fading out.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_seconds_to_play_before_fading_out(handle: *const SnesApu) -> i32 {
+    if handle.is_null() {
+        return 0;
+    }
+    (*handle).seconds_to_play_before_fading_out
+}
+
+/// Fade out length in milliseconds.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle from `snes_apu_load`.
+#[no_mangle]
+pub unsafe extern "C" fn snes_apu_fade_out_length(handle: *const SnesApu) -> i32 {
+    if handle.is_null() {
+        return 0;
+    }
+    (*handle).fade_out_length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> *mut SnesApu {
+        Box::into_raw(Box::new(SnesApu {
+            apu: Apu::new(),
+
+            song_title: to_c_string("Title\0hidden"),
+            game_title: to_c_string("Game"),
+            artist_name: CString::default(),
+            dumper_name: CString::default(),
+            comments: CString::default(),
+            seconds_to_play_before_fading_out: 180,
+            fade_out_length: 10000,
+        }))
+    }
+
+    #[test]
+    fn null_handles_are_ignored() {
+        unsafe {
+            assert!(snes_apu_load(ptr::null()).is_null());
+            let path = CString::new("/nonexistent/file.spc").unwrap();
+            assert!(snes_apu_load(path.as_ptr()).is_null());
+
+            let mut buffer = [1i16; 8];
+            snes_apu_render(ptr::null_mut(), buffer.as_mut_ptr(), 4);
+            assert_eq!(buffer, [1; 8]);
+            snes_apu_free(ptr::null_mut());
+
+            assert!(snes_apu_song_title(ptr::null()).is_null());
+            assert!(snes_apu_game_title(ptr::null()).is_null());
+            assert!(snes_apu_artist_name(ptr::null()).is_null());
+            assert!(snes_apu_dumper_name(ptr::null()).is_null());
+            assert!(snes_apu_comments(ptr::null()).is_null());
+            assert_eq!(snes_apu_seconds_to_play_before_fading_out(ptr::null()), 0);
+            assert_eq!(snes_apu_fade_out_length(ptr::null()), 0);
+        }
+    }
+
+    #[test]
+    fn getters_read_the_handle() {
+        unsafe {
+            let handle = handle();
+            let title = CStr::from_ptr(snes_apu_song_title(handle));
+            assert_eq!(title.to_str(), Ok("Title"));
+            let game = CStr::from_ptr(snes_apu_game_title(handle));
+            assert_eq!(game.to_str(), Ok("Game"));
+            let artist = CStr::from_ptr(snes_apu_artist_name(handle));
+            assert_eq!(artist.to_str(), Ok(""));
+            assert_eq!(snes_apu_seconds_to_play_before_fading_out(handle), 180);
+            assert_eq!(snes_apu_fade_out_length(handle), 10000);
+
+            let mut buffer = [1i16; 9];
+            snes_apu_render(handle, buffer.as_mut_ptr(), 4);
+            assert_eq!(buffer, [0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+            snes_apu_free(handle);
+        }
+    }
+}
@@ -1,7 +1,23 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(bigint_helper_methods)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std as alloc;
+
+#[cfg(feature = "std")]
 extern crate spc;
 
 pub mod apu;
+pub mod disasm;
 pub mod dsp;
+#[cfg(feature = "capi")]
+pub mod ffi;
+mod resampler;
 pub mod smp;
-mod timer;
+mod state;
+pub mod timer;
+#[cfg(feature = "wav")]
+mod wav;
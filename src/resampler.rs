@@ -0,0 +1,162 @@
+use alloc::collections::VecDeque;
+
+use super::apu::LoadStateError;
+use super::dsp::dsp::SAMPLE_RATE;
+use super::state::{StateReader, StateWriter};
+
+const FRACTION_BITS: u32 = 32;
+const FRACTION_MASK: u64 = (1 << FRACTION_BITS) - 1;
+
+pub struct Resampler {
+    output_sample_rate: u32,
+
+    step: u64,
+    pos: u64,
+    last_sample: (i16, i16),
+    next_sample: (i16, i16),
+}
+
+impl Resampler {
+    pub fn new(output_sample_rate: u32) -> Resampler {
+        Resampler {
+            output_sample_rate,
+
+            step: ((SAMPLE_RATE as u64) << FRACTION_BITS) / (output_sample_rate as u64),
+            // Start two samples "behind" so the first output lands exactly on the first input
+            pos: 2 << FRACTION_BITS,
+            last_sample: (0, 0),
+            next_sample: (0, 0),
+        }
+    }
+
+    pub fn output_sample_rate(&self) -> u32 {
+        self.output_sample_rate
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.output_sample_rate);
+        writer.write_u64(self.pos);
+        for sample in [self.last_sample, self.next_sample] {
+            writer.write_i16(sample.0);
+            writer.write_i16(sample.1);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        let output_sample_rate = reader.read_u32()?;
+        if output_sample_rate == 0 {
+            return Err(LoadStateError::Invalid);
+        }
+        let mut ret = Resampler::new(output_sample_rate);
+        ret.pos = reader.read_u64()?;
+        // `next` leaves pos less than a step past the current input frame, and a fresh
+        //  resampler starts two frames back; anything further out is garbage.
+        if ret.pos > (2 << FRACTION_BITS).max((1 << FRACTION_BITS) + ret.step) {
+            return Err(LoadStateError::Invalid);
+        }
+        ret.last_sample = (reader.read_i16()?, reader.read_i16()?);
+        ret.next_sample = (reader.read_i16()?, reader.read_i16()?);
+        *self = ret;
+        Ok(())
+    }
+
+    fn is_passthrough(&self) -> bool {
+        self.output_sample_rate as usize == SAMPLE_RATE
+    }
+
+    pub fn input_frames_needed(&self, num_output_frames: usize) -> usize {
+        if self.is_passthrough() {
+            return num_output_frames;
+        }
+
+        match num_output_frames {
+            0 => 0,
+            n => ((self.pos + ((n - 1) as u64) * self.step) >> FRACTION_BITS) as usize,
+        }
+    }
+
+    pub fn next(&mut self, input: &mut VecDeque<(i16, i16)>) -> (i16, i16) {
+        if self.is_passthrough() {
+            return input.pop_front().unwrap_or_default();
+        }
+
+        while self.pos >= (1 << FRACTION_BITS) {
+            self.last_sample = self.next_sample;
+            self.next_sample = input.pop_front().unwrap_or(self.last_sample);
+            self.pos -= 1 << FRACTION_BITS;
+        }
+
+        let fraction = (self.pos & FRACTION_MASK) as i64;
+        let ret = (
+            Resampler::interpolate(self.last_sample.0, self.next_sample.0, fraction),
+            Resampler::interpolate(self.last_sample.1, self.next_sample.1, fraction),
+        );
+        self.pos += self.step;
+        ret
+    }
+
+    fn interpolate(a: i16, b: i16, fraction: i64) -> i16 {
+        let a = a as i64;
+        let b = b as i64;
+        (a + (((b - a) * fraction) >> FRACTION_BITS)) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn save(resampler: &Resampler) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        resampler.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut input: VecDeque<(i16, i16)> = (0..64).map(|i| (i * 100, -i * 100)).collect();
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..10 {
+            resampler.next(&mut input);
+        }
+
+        let mut loaded = Resampler::new(SAMPLE_RATE as u32);
+        loaded
+            .load_state(&mut StateReader::new(&save(&resampler)))
+            .unwrap();
+        assert_eq!(loaded.output_sample_rate(), 44100);
+
+        let mut loaded_input = input.clone();
+        for _ in 0..20 {
+            assert_eq!(loaded.next(&mut loaded_input), resampler.next(&mut input));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_state() {
+        let mut state = save(&Resampler::new(44100));
+
+        let mut zero_rate = state.clone();
+        zero_rate[0..4].copy_from_slice(&0u32.to_le_bytes());
+        let mut resampler = Resampler::new(44100);
+        assert_eq!(
+            resampler.load_state(&mut StateReader::new(&zero_rate)),
+            Err(LoadStateError::Invalid)
+        );
+
+        state[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            resampler.load_state(&mut StateReader::new(&state)),
+            Err(LoadStateError::Invalid)
+        );
+        // A failed load leaves the resampler as it was
+        assert_eq!(resampler.pos, 2 << FRACTION_BITS);
+
+        assert_eq!(
+            resampler.load_state(&mut StateReader::new(&state[..6])),
+            Err(LoadStateError::Truncated)
+        );
+    }
+}
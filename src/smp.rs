@@ -1,9 +1,33 @@
-use std::rc::Weak;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use core::ptr;
+
+use super::apu::{Apu, LoadStateError};
+use super::state::{StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpRegisters {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub psw: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    ReadWatchpoint(u16),
+    WriteWatchpoint(u16),
+}
 
-use super::apu::Apu;
+pub type TraceCallback = Box<dyn FnMut(u16, u8, &SmpRegisters) + Send>;
 
-pub struct Smp<'apu> {
-    emulator: Weak<Apu<'apu>>,
+pub struct Smp {
+    // Only set for the duration of `run` or `step`, which are handed the APU to run
+    //  against; it's null the rest of the time.
+    emulator: *mut Apu,
 
     pub reg_pc: u16,
     pub reg_a: u8,
@@ -22,13 +46,26 @@ pub struct Smp<'apu> {
 
     is_stopped: bool,
 
-    cycle_count: usize,
+    run_cycle_count: usize,
+    total_cycle_count: u64,
+
+    trace_callback: Option<TraceCallback>,
+
+    breakpoints: BTreeSet<u16>,
+    read_watchpoints: BTreeSet<u16>,
+    write_watchpoints: BTreeSet<u16>,
+    break_reason: Option<BreakReason>,
 }
 
-impl Smp<'_> {
-    pub fn new(emulator: Weak<Apu>) -> Smp {
+// SAFETY: `emulator` is the only thing stopping this from being Send automatically. It's
+//  only non-null inside `run` and `step`, which hold the `&mut Apu` it points to for the
+//  whole call, so it never outlives that borrow and can't be seen from another thread.
+unsafe impl Send for Smp {}
+
+impl Smp {
+    pub fn new() -> Smp {
         Smp {
-            emulator: emulator,
+            emulator: ptr::null_mut(),
 
             reg_pc: 0xffc0,
             reg_a: 0,
@@ -47,10 +84,153 @@ impl Smp<'_> {
 
             is_stopped: false,
 
-            cycle_count: 0,
+            run_cycle_count: 0,
+            total_cycle_count: 0,
+
+            trace_callback: None,
+
+            breakpoints: BTreeSet::new(),
+            read_watchpoints: BTreeSet::new(),
+            write_watchpoints: BTreeSet::new(),
+            break_reason: None,
         }
     }
 
+    pub fn reset(&mut self) {
+        self.reg_pc = 0xffc0;
+        self.reg_a = 0;
+        self.reg_x = 0;
+        self.reg_y = 0;
+        self.reg_sp = 0xef;
+
+        self.psw_c = false;
+        self.psw_z = true;
+        self.psw_h = false;
+        self.psw_p = false;
+        self.psw_v = false;
+        self.psw_n = false;
+        self.psw_i = false;
+        self.psw_b = false;
+
+        self.is_stopped = false;
+
+        self.run_cycle_count = 0;
+        self.total_cycle_count = 0;
+    }
+
+    /// Cycles run since power-on or the last `reset_cycle_count`. Diffing this across two
+    /// breakpoints gives the cost of the code in between.
+    pub fn cycle_count(&self) -> u64 {
+        self.total_cycle_count
+    }
+
+    pub fn reset_cycle_count(&mut self) {
+        self.total_cycle_count = 0;
+    }
+
+    /// Sets a callback that's invoked with the PC, opcode, and register state before
+    /// each instruction executes.
+    pub fn set_trace_callback(&mut self, callback: TraceCallback) {
+        self.trace_callback = Some(callback);
+    }
+
+    pub fn clear_trace_callback(&mut self) {
+        self.trace_callback = None;
+    }
+
+    pub fn registers(&self) -> SmpRegisters {
+        SmpRegisters {
+            pc: self.reg_pc,
+            a: self.reg_a,
+            x: self.reg_x,
+            y: self.reg_y,
+            sp: self.reg_sp,
+            psw: self.get_psw(),
+        }
+    }
+
+    pub fn set_registers(&mut self, regs: SmpRegisters) {
+        self.reg_pc = regs.pc;
+        self.reg_a = regs.a;
+        self.reg_x = regs.x;
+        self.reg_y = regs.y;
+        self.reg_sp = regs.sp;
+        self.set_psw(regs.psw);
+    }
+
+    // Debugging hooks (breakpoints, watchpoints, the trace callback) aren't part of the
+    //  saved state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        let regs = self.registers();
+        writer.write_u16(regs.pc);
+        writer.write_u8(regs.a);
+        writer.write_u8(regs.x);
+        writer.write_u8(regs.y);
+        writer.write_u8(regs.sp);
+        writer.write_u8(regs.psw);
+        writer.write_bool(self.is_stopped);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        self.set_registers(SmpRegisters {
+            pc: reader.read_u16()?,
+            a: reader.read_u8()?,
+            x: reader.read_u8()?,
+            y: reader.read_u8()?,
+            sp: reader.read_u8()?,
+            psw: reader.read_u8()?,
+        });
+        self.is_stopped = reader.read_bool()?;
+        Ok(())
+    }
+
+    fn trace(&mut self, pc: u16, opcode: u8) {
+        let registers = SmpRegisters {
+            pc,
+            ..self.registers()
+        };
+        if let Some(callback) = self.trace_callback.as_mut() {
+            callback(pc, opcode, &registers);
+        }
+    }
+
+    // Breakpoints and watchpoints only stop `Apu::run`. Rendering keeps running the SMP
+    //  until it has enough samples, so it goes straight past them.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn add_read_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.insert(addr);
+    }
+
+    pub fn remove_read_watchpoint(&mut self, addr: u16) {
+        self.read_watchpoints.remove(&addr);
+    }
+
+    pub fn add_write_watchpoint(&mut self, addr: u16) {
+        self.write_watchpoints.insert(addr);
+    }
+
+    pub fn remove_write_watchpoint(&mut self, addr: u16) {
+        self.write_watchpoints.remove(&addr);
+    }
+
+    /// Whether the SMP has executed SLEEP or STOP. It stays halted until reset, while the
+    /// DSP and timers carry on.
+    pub fn is_halted(&self) -> bool {
+        self.is_stopped
+    }
+
+    /// Why the last call to `Apu::run` returned early, if it did.
+    pub fn break_reason(&self) -> Option<BreakReason> {
+        self.break_reason
+    }
+
     pub fn set_reg_ya(&mut self, value: u16) {
         self.reg_a = value as u8;
         self.reg_y = (value >> 8) as u8;
@@ -63,7 +243,9 @@ impl Smp<'_> {
     pub fn set_psw(&mut self, value: u8) {
         self.psw_c = (value & 0x01) != 0;
         self.psw_z = (value & 0x02) != 0;
+        self.psw_i = (value & 0x04) != 0;
         self.psw_h = (value & 0x08) != 0;
+        self.psw_b = (value & 0x10) != 0;
         self.psw_p = (value & 0x20) != 0;
         self.psw_v = (value & 0x40) != 0;
         self.psw_n = (value & 0x80) != 0;
@@ -73,31 +255,80 @@ impl Smp<'_> {
         ((if self.psw_n { 1 } else { 0 }) << 7)
             | ((if self.psw_v { 1 } else { 0 }) << 6)
             | ((if self.psw_p { 1 } else { 0 }) << 5)
+            | ((if self.psw_b { 1 } else { 0 }) << 4)
             | ((if self.psw_h { 1 } else { 0 }) << 3)
+            | ((if self.psw_i { 1 } else { 0 }) << 2)
             | ((if self.psw_z { 1 } else { 0 }) << 1)
             | (if self.psw_c { 1 } else { 0 })
     }
 
+    /// Sets the P flag, which moves every direct-page access from $00xx to $01xx.
+    pub fn set_direct_page(&mut self, high: bool) {
+        self.psw_p = high;
+    }
+
+    pub fn flag_negative(&self) -> bool {
+        self.psw_n
+    }
+
+    pub fn flag_overflow(&self) -> bool {
+        self.psw_v
+    }
+
+    pub fn flag_direct_page(&self) -> bool {
+        self.psw_p
+    }
+
+    pub fn flag_break(&self) -> bool {
+        self.psw_b
+    }
+
+    pub fn flag_half_carry(&self) -> bool {
+        self.psw_h
+    }
+
+    pub fn flag_interrupt(&self) -> bool {
+        self.psw_i
+    }
+
+    pub fn flag_zero(&self) -> bool {
+        self.psw_z
+    }
+
+    pub fn flag_carry(&self) -> bool {
+        self.psw_c
+    }
+
+    #[inline]
+    fn emulator(&mut self) -> &mut Apu {
+        debug_assert!(!self.emulator.is_null());
+        unsafe { &mut (*self.emulator) }
+    }
+
     fn is_negative(value: u8) -> bool {
         (value & 0x80) != 0
     }
 
     fn cycles(&mut self, num_cycles: usize) {
-        self.emulator
-            .upgrade()
-            .unwrap()
-            .cpu_cycles_callback(num_cycles);
-        self.cycle_count += num_cycles;
+        self.emulator().cpu_cycles_callback(num_cycles);
+        self.run_cycle_count += num_cycles;
+        self.total_cycle_count += num_cycles as u64;
     }
 
     fn read(&mut self, addr: u16) -> u8 {
         self.cycles(1);
-        self.emulator.upgrade().unwrap().read_u8(addr)
+        if self.read_watchpoints.contains(&addr) {
+            self.break_reason = Some(BreakReason::ReadWatchpoint(addr));
+        }
+        self.emulator().read_u8(addr)
     }
 
     fn write(&mut self, addr: u16, value: u8) {
         self.cycles(1);
-        self.emulator.upgrade().unwrap().write_u8(addr, value);
+        if self.write_watchpoints.contains(&addr) {
+            self.break_reason = Some(BreakReason::WriteWatchpoint(addr));
+        }
+        self.emulator().write_u8(addr, value);
     }
 
     fn read_pc(&mut self) -> u8 {
@@ -429,6 +660,7 @@ impl Smp<'_> {
             self.reg_a = self.reg_a.wrapping_add(0x60);
             self.psw_c = true;
         }
+        // The low nibble check sees A after the high adjustment, and H is left untouched
         if self.psw_h || (self.reg_a & 0x0f) > 0x09 {
             self.reg_a = self.reg_a.wrapping_add(0x06);
         }
@@ -442,6 +674,7 @@ impl Smp<'_> {
             self.reg_a = self.reg_a.wrapping_sub(0x60);
             self.psw_c = false;
         }
+        // Same ordering and flag quirks as daa, with the carry/half-carry sense inverted
         if !self.psw_h || (self.reg_a & 0x0f) > 0x09 {
             self.reg_a = self.reg_a.wrapping_sub(0x06);
         }
@@ -454,8 +687,12 @@ impl Smp<'_> {
         let ya = self.get_reg_ya();
         self.psw_v = self.reg_y >= self.reg_x;
         self.psw_h = (self.reg_y & 0x0f) >= (self.reg_x & 0x0f);
-        let reg_x = self.reg_x as u16;
-        if (self.reg_y as u16) < (reg_x << 1) {
+        // The hardware divider only produces a 9-bit quotient. When that overflows
+        //  (including X = 0) it falls back to this odd but well-defined result rather than
+        //  anything resembling a real division.
+        let ya = ya as u32;
+        let reg_x = self.reg_x as u32;
+        if (self.reg_y as u32) < (reg_x << 1) {
             self.reg_a = (ya / reg_x) as u8;
             self.reg_y = (ya % reg_x) as u8;
         } else {
@@ -560,7 +797,7 @@ impl Smp<'_> {
     }
 
     fn sta_i_dp_x(&mut self) {
-        let mut addr = self.read_pc() + self.reg_x;
+        let mut addr = self.read_pc().wrapping_add(self.reg_x);
         self.cycles(1);
         let mut addr2 = self.read_dp(addr) as u16;
         addr = addr.wrapping_add(1);
@@ -615,12 +852,61 @@ impl Smp<'_> {
 
     fn xcn(&mut self) {
         self.cycles(4);
-        self.reg_a = (self.reg_a >> 4) | (self.reg_a << 4);
+        self.reg_a = self.reg_a.rotate_right(4);
         let reg_a = self.reg_a;
         self.set_psw_n_z(reg_a);
     }
 
-    pub fn run(&mut self, target_cycles: usize) -> usize {
+    /// Runs until at least `target_cycles` cycles have elapsed, or until a breakpoint or
+    /// watchpoint is hit (see `break_reason`). Returns the number of cycles actually run,
+    /// which overshoots the target by less than one instruction (at most 12 cycles).
+    ///
+    /// Breakpoints are checked between instructions, so calling `run` again after
+    /// stopping on one will step past it.
+    ///
+    /// `emulator` is the APU this SMP belongs to, with the SMP itself taken out of it for
+    /// the duration of the call (see `Apu::run`).
+    pub(crate) fn run(&mut self, emulator: &mut Apu, target_cycles: usize) -> usize {
+        self.emulator = emulator;
+        self.run_cycles(target_cycles);
+        self.emulator = ptr::null_mut();
+        self.run_cycle_count
+    }
+
+    /// Executes a single instruction against `emulator` and returns the number of cycles
+    /// it took.
+    pub(crate) fn step(&mut self, emulator: &mut Apu) -> usize {
+        self.emulator = emulator;
+        let cycles = self.execute();
+        self.emulator = ptr::null_mut();
+        cycles
+    }
+
+    fn run_cycles(&mut self, target_cycles: usize) {
+        self.run_cycle_count = 0;
+        self.break_reason = None;
+        while self.run_cycle_count < target_cycles {
+            if self.is_stopped {
+                // Nothing but a reset wakes the SMP back up, so skip straight to the end
+                //  of the budget; the rest of the APU keeps getting clocked regardless.
+                let remaining = target_cycles - self.run_cycle_count;
+                self.cycles(remaining);
+                break;
+            }
+
+            self.execute();
+
+            if self.break_reason.is_some() {
+                break;
+            }
+            if self.breakpoints.contains(&self.reg_pc) {
+                self.break_reason = Some(BreakReason::Breakpoint(self.reg_pc));
+                break;
+            }
+        }
+    }
+
+    fn execute(&mut self) -> usize {
         macro_rules! adjust {
             ($op:ident, $x:expr) => {{
                 self.cycles(1);
@@ -877,326 +1163,698 @@ impl Smp<'_> {
             }};
         }
 
-        self.cycle_count = 0;
-        while self.cycle_count < target_cycles {
-            if !self.is_stopped {
-                let opcode = self.read_pc();
-                match opcode {
-                    0x00 => self.nop(),
-                    0x01 => self.jst(opcode),
-                    0x02 => self.set_bit(opcode),
-                    0x03 => self.branch_bit(opcode),
-                    0x04 => read_dp!(or, self.reg_a),
-                    0x05 => read_addr!(or, self.reg_a),
-                    0x06 => read_i_x!(or),
-                    0x07 => read_i_dp_x!(or),
-                    0x08 => read_const!(or, self.reg_a),
-                    0x09 => write_dp_dp!(or, false, false),
-                    0x0a => self.set_addr_bit(opcode),
-                    0x0b => adjust_dp!(asl),
-                    0x0c => adjust_addr!(asl),
-                    0x0d => {
-                        let psw = self.get_psw();
-                        self.push(psw);
-                    }
-                    0x0e => self.test_addr(true),
-                    0x0f => self.brk(),
-
-                    0x10 => {
-                        let psw_n = self.psw_n;
-                        self.branch(!psw_n);
-                    }
-                    0x11 => self.jst(opcode),
-                    0x12 => self.set_bit(opcode),
-                    0x13 => self.branch_bit(opcode),
-                    0x14 => read_dp_i!(or, self.reg_a, self.reg_x),
-                    0x15 => read_addr_i!(or, self.reg_x),
-                    0x16 => read_addr_i!(or, self.reg_y),
-                    0x17 => read_i_dp_y!(or),
-                    0x18 => write_dp_const!(or, false),
-                    0x19 => write_i_x_i_y!(or, false),
-                    0x1a => self.adjust_dpw(!0),
-                    0x1b => adjust_dp_x!(asl),
-                    0x1c => adjust!(asl, self.reg_a),
-                    0x1d => adjust!(dec, self.reg_x),
-                    0x1e => read_addr!(cmp, self.reg_x),
-                    0x1f => self.jmp_i_addr_x(),
-
-                    0x20 => set_flag!(self.psw_p, false, false),
-                    0x21 => self.jst(opcode),
-                    0x22 => self.set_bit(opcode),
-                    0x23 => self.branch_bit(opcode),
-                    0x24 => read_dp!(and, self.reg_a),
-                    0x25 => read_addr!(and, self.reg_a),
-                    0x26 => read_i_x!(and),
-                    0x27 => read_i_dp_x!(and),
-                    0x28 => read_const!(and, self.reg_a),
-                    0x29 => write_dp_dp!(and, false, false),
-                    0x2a => self.set_addr_bit(opcode),
-                    0x2b => adjust_dp!(rol),
-                    0x2c => adjust_addr!(rol),
-                    0x2d => {
-                        let reg_a = self.reg_a;
-                        self.push(reg_a);
-                    }
-                    0x2e => self.bne_dp(),
-                    0x2f => self.branch(true),
-
-                    0x30 => {
-                        let psw_n = self.psw_n;
-                        self.branch(psw_n);
-                    }
-                    0x31 => self.jst(opcode),
-                    0x32 => self.set_bit(opcode),
-                    0x33 => self.branch_bit(opcode),
-                    0x34 => read_dp_i!(and, self.reg_a, self.reg_x),
-                    0x35 => read_addr_i!(and, self.reg_x),
-                    0x36 => read_addr_i!(and, self.reg_y),
-                    0x37 => read_i_dp_y!(and),
-                    0x38 => write_dp_const!(and, false),
-                    0x39 => write_i_x_i_y!(and, false),
-                    0x3a => self.adjust_dpw(1),
-                    0x3b => adjust_dp_x!(rol),
-                    0x3c => adjust!(rol, self.reg_a),
-                    0x3d => adjust!(inc, self.reg_x),
-                    0x3e => read_dp!(cmp, self.reg_x),
-                    0x3f => self.jsr_addr(),
-
-                    0x40 => set_flag!(self.psw_p, true, false),
-                    0x41 => self.jst(opcode),
-                    0x42 => self.set_bit(opcode),
-                    0x43 => self.branch_bit(opcode),
-                    0x44 => read_dp!(eor, self.reg_a),
-                    0x45 => read_addr!(eor, self.reg_a),
-                    0x46 => read_i_x!(eor),
-                    0x47 => read_i_dp_x!(eor),
-                    0x48 => read_const!(eor, self.reg_a),
-                    0x49 => write_dp_dp!(eor, false, false),
-                    0x4a => self.set_addr_bit(opcode),
-                    0x4b => adjust_dp!(lsr),
-                    0x4c => adjust_addr!(lsr),
-                    0x4d => {
-                        let reg_x = self.reg_x;
-                        self.push(reg_x);
-                    }
-                    0x4e => self.test_addr(false),
-                    0x4f => self.jsp_dp(),
-
-                    0x50 => {
-                        let psw_v = self.psw_v;
-                        self.branch(!psw_v);
-                    }
-                    0x51 => self.jst(opcode),
-                    0x52 => self.set_bit(opcode),
-                    0x53 => self.branch_bit(opcode),
-                    0x54 => read_dp_i!(eor, self.reg_a, self.reg_x),
-                    0x55 => read_addr_i!(eor, self.reg_x),
-                    0x56 => read_addr_i!(eor, self.reg_y),
-                    0x57 => read_i_dp_y!(eor),
-                    0x58 => write_dp_const!(eor, false),
-                    0x59 => write_i_x_i_y!(eor, false),
-                    0x5a => read_dpw!(cpw, true),
-                    0x5b => adjust_dp_x!(lsr),
-                    0x5c => adjust!(lsr, self.reg_a),
-                    0x5d => transfer!(self.reg_a, self.reg_x, false),
-                    0x5e => read_addr!(cmp, self.reg_y),
-                    0x5f => self.jmp_addr(),
-
-                    0x60 => set_flag!(self.psw_c, false, false),
-                    0x61 => self.jst(opcode),
-                    0x62 => self.set_bit(opcode),
-                    0x63 => self.branch_bit(opcode),
-                    0x64 => read_dp!(cmp, self.reg_a),
-                    0x65 => read_addr!(cmp, self.reg_a),
-                    0x66 => read_i_x!(cmp),
-                    0x67 => read_i_dp_x!(cmp),
-                    0x68 => read_const!(cmp, self.reg_a),
-                    0x69 => write_dp_dp!(cmp, true, false),
-                    0x6a => self.set_addr_bit(opcode),
-                    0x6b => adjust_dp!(ror),
-                    0x6c => adjust_addr!(ror),
-                    0x6d => {
-                        let reg_y = self.reg_y;
-                        self.push(reg_y);
-                    }
-                    0x6e => self.bne_dp_dec(),
-                    0x6f => self.rts(),
-
-                    0x70 => {
-                        let psw_v = self.psw_v;
-                        self.branch(psw_v);
-                    }
-                    0x71 => self.jst(opcode),
-                    0x72 => self.set_bit(opcode),
-                    0x73 => self.branch_bit(opcode),
-                    0x74 => read_dp_i!(cmp, self.reg_a, self.reg_x),
-                    0x75 => read_addr_i!(cmp, self.reg_x),
-                    0x76 => read_addr_i!(cmp, self.reg_y),
-                    0x77 => read_i_dp_y!(cmp),
-                    0x78 => write_dp_const!(cmp, true),
-                    0x79 => write_i_x_i_y!(cmp, true),
-                    0x7a => read_dpw!(adw, false),
-                    0x7b => adjust_dp_x!(ror),
-                    0x7c => adjust!(ror, self.reg_a),
-                    0x7d => transfer!(self.reg_x, self.reg_a, false),
-                    0x7e => read_dp!(cmp, self.reg_y),
-                    0x7f => self.rti(),
-
-                    0x80 => set_flag!(self.psw_c, true, false),
-                    0x81 => self.jst(opcode),
-                    0x82 => self.set_bit(opcode),
-                    0x83 => self.branch_bit(opcode),
-                    0x84 => read_dp!(adc, self.reg_a),
-                    0x85 => read_addr!(adc, self.reg_a),
-                    0x86 => read_i_x!(adc),
-                    0x87 => read_i_dp_x!(adc),
-                    0x88 => read_const!(adc, self.reg_a),
-                    0x89 => write_dp_dp!(adc, false, false),
-                    0x8a => self.set_addr_bit(opcode),
-                    0x8b => adjust_dp!(dec),
-                    0x8c => adjust_addr!(dec),
-                    0x8d => read_const!(ld, self.reg_y),
-                    0x8e => self.plp(),
-                    0x8f => write_dp_const!(st, false),
-
-                    0x90 => {
-                        let psw_c = self.psw_c;
-                        self.branch(!psw_c);
-                    }
-                    0x91 => self.jst(opcode),
-                    0x92 => self.set_bit(opcode),
-                    0x93 => self.branch_bit(opcode),
-                    0x94 => read_dp_i!(adc, self.reg_a, self.reg_x),
-                    0x95 => read_addr_i!(adc, self.reg_x),
-                    0x96 => read_addr_i!(adc, self.reg_y),
-                    0x97 => read_i_dp_y!(adc),
-                    0x98 => write_dp_const!(adc, false),
-                    0x99 => write_i_x_i_y!(adc, false),
-                    0x9a => read_dpw!(sbw, false),
-                    0x9b => adjust_dp_x!(dec),
-                    0x9c => adjust!(dec, self.reg_a),
-                    0x9d => transfer!(self.reg_sp, self.reg_x, false),
-                    0x9e => self.div_ya(),
-                    0x9f => self.xcn(),
-
-                    0xa0 => set_flag!(self.psw_i, true, true),
-                    0xa1 => self.jst(opcode),
-                    0xa2 => self.set_bit(opcode),
-                    0xa3 => self.branch_bit(opcode),
-                    0xa4 => read_dp!(sbc, self.reg_a),
-                    0xa5 => read_addr!(sbc, self.reg_a),
-                    0xa6 => read_i_x!(sbc),
-                    0xa7 => read_i_dp_x!(sbc),
-                    0xa8 => read_const!(sbc, self.reg_a),
-                    0xa9 => write_dp_dp!(sbc, false, false),
-                    0xaa => self.set_addr_bit(opcode),
-                    0xab => adjust_dp!(inc),
-                    0xac => adjust_addr!(inc),
-                    0xad => read_const!(cmp, self.reg_y),
-                    0xae => pull!(self.reg_a),
-                    0xaf => self.sta_i_x_inc(),
-
-                    0xb0 => {
-                        let psw_c = self.psw_c;
-                        self.branch(psw_c);
-                    }
-                    0xb1 => self.jst(opcode),
-                    0xb2 => self.set_bit(opcode),
-                    0xb3 => self.branch_bit(opcode),
-                    0xb4 => read_dp_i!(sbc, self.reg_a, self.reg_x),
-                    0xb5 => read_addr_i!(sbc, self.reg_x),
-                    0xb6 => read_addr_i!(sbc, self.reg_y),
-                    0xb7 => read_i_dp_y!(sbc),
-                    0xb8 => write_dp_const!(sbc, false),
-                    0xb9 => write_i_x_i_y!(sbc, false),
-                    0xba => read_dpw!(ldw, false),
-                    0xbb => adjust_dp_x!(inc),
-                    0xbc => adjust!(inc, self.reg_a),
-                    0xbd => transfer!(self.reg_x, self.reg_sp, true),
-                    0xbe => self.das(),
-                    0xbf => self.lda_i_x_inc(),
-
-                    0xc0 => set_flag!(self.psw_i, false, true),
-                    0xc1 => self.jst(opcode),
-                    0xc2 => self.set_bit(opcode),
-                    0xc3 => self.branch_bit(opcode),
-                    0xc4 => write_dp_imm!(self.reg_a),
-                    0xc5 => write_addr!(self.reg_a),
-                    0xc6 => self.sta_i_x(),
-                    0xc7 => self.sta_i_dp_x(),
-                    0xc8 => read_const!(cmp, self.reg_x),
-                    0xc9 => write_addr!(self.reg_x),
-                    0xca => self.set_addr_bit(opcode),
-                    0xcb => write_dp_imm!(self.reg_y),
-                    0xcc => write_addr!(self.reg_y),
-                    0xcd => read_const!(ld, self.reg_x),
-                    0xce => pull!(self.reg_x),
-                    0xcf => self.mul_ya(),
-
-                    0xd0 => {
-                        let psw_z = self.psw_z;
-                        self.branch(!psw_z);
-                    }
-                    0xd1 => self.jst(opcode),
-                    0xd2 => self.set_bit(opcode),
-                    0xd3 => self.branch_bit(opcode),
-                    0xd4 => write_dp_i!(self.reg_a, self.reg_x),
-                    0xd5 => write_addr_i!(self.reg_x),
-                    0xd6 => write_addr_i!(self.reg_y),
-                    0xd7 => self.sta_i_dp_y(),
-                    0xd8 => write_dp_imm!(self.reg_x),
-                    0xd9 => write_dp_i!(self.reg_x, self.reg_y),
-                    0xda => self.stw_dp(),
-                    0xdb => write_dp_i!(self.reg_y, self.reg_x),
-                    0xdc => adjust!(dec, self.reg_y),
-                    0xdd => transfer!(self.reg_y, self.reg_a, false),
-                    0xde => self.bne_dp_x(),
-                    0xdf => self.daa(),
-
-                    0xe0 => self.clv(),
-                    0xe1 => self.jst(opcode),
-                    0xe2 => self.set_bit(opcode),
-                    0xe3 => self.branch_bit(opcode),
-                    0xe4 => read_dp!(ld, self.reg_a),
-                    0xe5 => read_addr!(ld, self.reg_a),
-                    0xe6 => read_i_x!(ld),
-                    0xe7 => read_i_dp_x!(ld),
-                    0xe8 => read_const!(ld, self.reg_a),
-                    0xe9 => read_addr!(ld, self.reg_x),
-                    0xea => self.set_addr_bit(opcode),
-                    0xeb => read_dp!(ld, self.reg_y),
-                    0xec => read_addr!(ld, self.reg_y),
-                    0xed => self.cmc(),
-                    0xee => pull!(self.reg_y),
-                    0xef => self.sleep_stop(),
-
-                    0xf0 => {
-                        let psw_z = self.psw_z;
-                        self.branch(psw_z);
-                    }
-                    0xf1 => self.jst(opcode),
-                    0xf2 => self.set_bit(opcode),
-                    0xf3 => self.branch_bit(opcode),
-                    0xf4 => read_dp_i!(ld, self.reg_a, self.reg_x),
-                    0xf5 => read_addr_i!(ld, self.reg_x),
-                    0xf6 => read_addr_i!(ld, self.reg_y),
-                    0xf7 => read_i_dp_y!(ld),
-                    0xf8 => read_dp!(ld, self.reg_x),
-                    0xf9 => read_dp_i!(ld, self.reg_x, self.reg_y),
-                    0xfa => write_dp_dp!(st, false, true),
-                    0xfb => read_dp_i!(ld, self.reg_y, self.reg_x),
-                    0xfc => adjust!(inc, self.reg_y),
-                    0xfd => transfer!(self.reg_a, self.reg_y, false),
-                    0xfe => self.bne_y_dec(),
-                    0xff => self.sleep_stop(),
-
-                    _ => panic!("Invalid opcode"),
+        let start_cycle_count = self.run_cycle_count;
+        if !self.is_stopped {
+            let pc = self.reg_pc;
+            let opcode = self.read_pc();
+            if self.trace_callback.is_some() {
+                self.trace(pc, opcode);
+            }
+            match opcode {
+                0x00 => self.nop(),
+                0x01 => self.jst(opcode),
+                0x02 => self.set_bit(opcode),
+                0x03 => self.branch_bit(opcode),
+                0x04 => read_dp!(or, self.reg_a),
+                0x05 => read_addr!(or, self.reg_a),
+                0x06 => read_i_x!(or),
+                0x07 => read_i_dp_x!(or),
+                0x08 => read_const!(or, self.reg_a),
+                0x09 => write_dp_dp!(or, false, false),
+                0x0a => self.set_addr_bit(opcode),
+                0x0b => adjust_dp!(asl),
+                0x0c => adjust_addr!(asl),
+                0x0d => {
+                    let psw = self.get_psw();
+                    self.push(psw);
                 }
-            } else {
-                self.cycles(2);
+                0x0e => self.test_addr(true),
+                0x0f => self.brk(),
+
+                0x10 => {
+                    let psw_n = self.psw_n;
+                    self.branch(!psw_n);
+                }
+                0x11 => self.jst(opcode),
+                0x12 => self.set_bit(opcode),
+                0x13 => self.branch_bit(opcode),
+                0x14 => read_dp_i!(or, self.reg_a, self.reg_x),
+                0x15 => read_addr_i!(or, self.reg_x),
+                0x16 => read_addr_i!(or, self.reg_y),
+                0x17 => read_i_dp_y!(or),
+                0x18 => write_dp_const!(or, false),
+                0x19 => write_i_x_i_y!(or, false),
+                0x1a => self.adjust_dpw(!0),
+                0x1b => adjust_dp_x!(asl),
+                0x1c => adjust!(asl, self.reg_a),
+                0x1d => adjust!(dec, self.reg_x),
+                0x1e => read_addr!(cmp, self.reg_x),
+                0x1f => self.jmp_i_addr_x(),
+
+                0x20 => set_flag!(self.psw_p, false, false),
+                0x21 => self.jst(opcode),
+                0x22 => self.set_bit(opcode),
+                0x23 => self.branch_bit(opcode),
+                0x24 => read_dp!(and, self.reg_a),
+                0x25 => read_addr!(and, self.reg_a),
+                0x26 => read_i_x!(and),
+                0x27 => read_i_dp_x!(and),
+                0x28 => read_const!(and, self.reg_a),
+                0x29 => write_dp_dp!(and, false, false),
+                0x2a => self.set_addr_bit(opcode),
+                0x2b => adjust_dp!(rol),
+                0x2c => adjust_addr!(rol),
+                0x2d => {
+                    let reg_a = self.reg_a;
+                    self.push(reg_a);
+                }
+                0x2e => self.bne_dp(),
+                0x2f => self.branch(true),
+
+                0x30 => {
+                    let psw_n = self.psw_n;
+                    self.branch(psw_n);
+                }
+                0x31 => self.jst(opcode),
+                0x32 => self.set_bit(opcode),
+                0x33 => self.branch_bit(opcode),
+                0x34 => read_dp_i!(and, self.reg_a, self.reg_x),
+                0x35 => read_addr_i!(and, self.reg_x),
+                0x36 => read_addr_i!(and, self.reg_y),
+                0x37 => read_i_dp_y!(and),
+                0x38 => write_dp_const!(and, false),
+                0x39 => write_i_x_i_y!(and, false),
+                0x3a => self.adjust_dpw(1),
+                0x3b => adjust_dp_x!(rol),
+                0x3c => adjust!(rol, self.reg_a),
+                0x3d => adjust!(inc, self.reg_x),
+                0x3e => read_dp!(cmp, self.reg_x),
+                0x3f => self.jsr_addr(),
+
+                0x40 => set_flag!(self.psw_p, true, false),
+                0x41 => self.jst(opcode),
+                0x42 => self.set_bit(opcode),
+                0x43 => self.branch_bit(opcode),
+                0x44 => read_dp!(eor, self.reg_a),
+                0x45 => read_addr!(eor, self.reg_a),
+                0x46 => read_i_x!(eor),
+                0x47 => read_i_dp_x!(eor),
+                0x48 => read_const!(eor, self.reg_a),
+                0x49 => write_dp_dp!(eor, false, false),
+                0x4a => self.set_addr_bit(opcode),
+                0x4b => adjust_dp!(lsr),
+                0x4c => adjust_addr!(lsr),
+                0x4d => {
+                    let reg_x = self.reg_x;
+                    self.push(reg_x);
+                }
+                0x4e => self.test_addr(false),
+                0x4f => self.jsp_dp(),
+
+                0x50 => {
+                    let psw_v = self.psw_v;
+                    self.branch(!psw_v);
+                }
+                0x51 => self.jst(opcode),
+                0x52 => self.set_bit(opcode),
+                0x53 => self.branch_bit(opcode),
+                0x54 => read_dp_i!(eor, self.reg_a, self.reg_x),
+                0x55 => read_addr_i!(eor, self.reg_x),
+                0x56 => read_addr_i!(eor, self.reg_y),
+                0x57 => read_i_dp_y!(eor),
+                0x58 => write_dp_const!(eor, false),
+                0x59 => write_i_x_i_y!(eor, false),
+                0x5a => read_dpw!(cpw, true),
+                0x5b => adjust_dp_x!(lsr),
+                0x5c => adjust!(lsr, self.reg_a),
+                0x5d => transfer!(self.reg_a, self.reg_x, false),
+                0x5e => read_addr!(cmp, self.reg_y),
+                0x5f => self.jmp_addr(),
+
+                0x60 => set_flag!(self.psw_c, false, false),
+                0x61 => self.jst(opcode),
+                0x62 => self.set_bit(opcode),
+                0x63 => self.branch_bit(opcode),
+                0x64 => read_dp!(cmp, self.reg_a),
+                0x65 => read_addr!(cmp, self.reg_a),
+                0x66 => read_i_x!(cmp),
+                0x67 => read_i_dp_x!(cmp),
+                0x68 => read_const!(cmp, self.reg_a),
+                0x69 => write_dp_dp!(cmp, true, false),
+                0x6a => self.set_addr_bit(opcode),
+                0x6b => adjust_dp!(ror),
+                0x6c => adjust_addr!(ror),
+                0x6d => {
+                    let reg_y = self.reg_y;
+                    self.push(reg_y);
+                }
+                0x6e => self.bne_dp_dec(),
+                0x6f => self.rts(),
+
+                0x70 => {
+                    let psw_v = self.psw_v;
+                    self.branch(psw_v);
+                }
+                0x71 => self.jst(opcode),
+                0x72 => self.set_bit(opcode),
+                0x73 => self.branch_bit(opcode),
+                0x74 => read_dp_i!(cmp, self.reg_a, self.reg_x),
+                0x75 => read_addr_i!(cmp, self.reg_x),
+                0x76 => read_addr_i!(cmp, self.reg_y),
+                0x77 => read_i_dp_y!(cmp),
+                0x78 => write_dp_const!(cmp, true),
+                0x79 => write_i_x_i_y!(cmp, true),
+                0x7a => read_dpw!(adw, false),
+                0x7b => adjust_dp_x!(ror),
+                0x7c => adjust!(ror, self.reg_a),
+                0x7d => transfer!(self.reg_x, self.reg_a, false),
+                0x7e => read_dp!(cmp, self.reg_y),
+                0x7f => self.rti(),
+
+                0x80 => set_flag!(self.psw_c, true, false),
+                0x81 => self.jst(opcode),
+                0x82 => self.set_bit(opcode),
+                0x83 => self.branch_bit(opcode),
+                0x84 => read_dp!(adc, self.reg_a),
+                0x85 => read_addr!(adc, self.reg_a),
+                0x86 => read_i_x!(adc),
+                0x87 => read_i_dp_x!(adc),
+                0x88 => read_const!(adc, self.reg_a),
+                0x89 => write_dp_dp!(adc, false, false),
+                0x8a => self.set_addr_bit(opcode),
+                0x8b => adjust_dp!(dec),
+                0x8c => adjust_addr!(dec),
+                0x8d => read_const!(ld, self.reg_y),
+                0x8e => self.plp(),
+                0x8f => write_dp_const!(st, false),
+
+                0x90 => {
+                    let psw_c = self.psw_c;
+                    self.branch(!psw_c);
+                }
+                0x91 => self.jst(opcode),
+                0x92 => self.set_bit(opcode),
+                0x93 => self.branch_bit(opcode),
+                0x94 => read_dp_i!(adc, self.reg_a, self.reg_x),
+                0x95 => read_addr_i!(adc, self.reg_x),
+                0x96 => read_addr_i!(adc, self.reg_y),
+                0x97 => read_i_dp_y!(adc),
+                0x98 => write_dp_const!(adc, false),
+                0x99 => write_i_x_i_y!(adc, false),
+                0x9a => read_dpw!(sbw, false),
+                0x9b => adjust_dp_x!(dec),
+                0x9c => adjust!(dec, self.reg_a),
+                0x9d => transfer!(self.reg_sp, self.reg_x, false),
+                0x9e => self.div_ya(),
+                0x9f => self.xcn(),
+
+                0xa0 => set_flag!(self.psw_i, true, true),
+                0xa1 => self.jst(opcode),
+                0xa2 => self.set_bit(opcode),
+                0xa3 => self.branch_bit(opcode),
+                0xa4 => read_dp!(sbc, self.reg_a),
+                0xa5 => read_addr!(sbc, self.reg_a),
+                0xa6 => read_i_x!(sbc),
+                0xa7 => read_i_dp_x!(sbc),
+                0xa8 => read_const!(sbc, self.reg_a),
+                0xa9 => write_dp_dp!(sbc, false, false),
+                0xaa => self.set_addr_bit(opcode),
+                0xab => adjust_dp!(inc),
+                0xac => adjust_addr!(inc),
+                0xad => read_const!(cmp, self.reg_y),
+                0xae => pull!(self.reg_a),
+                0xaf => self.sta_i_x_inc(),
+
+                0xb0 => {
+                    let psw_c = self.psw_c;
+                    self.branch(psw_c);
+                }
+                0xb1 => self.jst(opcode),
+                0xb2 => self.set_bit(opcode),
+                0xb3 => self.branch_bit(opcode),
+                0xb4 => read_dp_i!(sbc, self.reg_a, self.reg_x),
+                0xb5 => read_addr_i!(sbc, self.reg_x),
+                0xb6 => read_addr_i!(sbc, self.reg_y),
+                0xb7 => read_i_dp_y!(sbc),
+                0xb8 => write_dp_const!(sbc, false),
+                0xb9 => write_i_x_i_y!(sbc, false),
+                0xba => read_dpw!(ldw, false),
+                0xbb => adjust_dp_x!(inc),
+                0xbc => adjust!(inc, self.reg_a),
+                0xbd => transfer!(self.reg_x, self.reg_sp, true),
+                0xbe => self.das(),
+                0xbf => self.lda_i_x_inc(),
+
+                0xc0 => set_flag!(self.psw_i, false, true),
+                0xc1 => self.jst(opcode),
+                0xc2 => self.set_bit(opcode),
+                0xc3 => self.branch_bit(opcode),
+                0xc4 => write_dp_imm!(self.reg_a),
+                0xc5 => write_addr!(self.reg_a),
+                0xc6 => self.sta_i_x(),
+                0xc7 => self.sta_i_dp_x(),
+                0xc8 => read_const!(cmp, self.reg_x),
+                0xc9 => write_addr!(self.reg_x),
+                0xca => self.set_addr_bit(opcode),
+                0xcb => write_dp_imm!(self.reg_y),
+                0xcc => write_addr!(self.reg_y),
+                0xcd => read_const!(ld, self.reg_x),
+                0xce => pull!(self.reg_x),
+                0xcf => self.mul_ya(),
+
+                0xd0 => {
+                    let psw_z = self.psw_z;
+                    self.branch(!psw_z);
+                }
+                0xd1 => self.jst(opcode),
+                0xd2 => self.set_bit(opcode),
+                0xd3 => self.branch_bit(opcode),
+                0xd4 => write_dp_i!(self.reg_a, self.reg_x),
+                0xd5 => write_addr_i!(self.reg_x),
+                0xd6 => write_addr_i!(self.reg_y),
+                0xd7 => self.sta_i_dp_y(),
+                0xd8 => write_dp_imm!(self.reg_x),
+                0xd9 => write_dp_i!(self.reg_x, self.reg_y),
+                0xda => self.stw_dp(),
+                0xdb => write_dp_i!(self.reg_y, self.reg_x),
+                0xdc => adjust!(dec, self.reg_y),
+                0xdd => transfer!(self.reg_y, self.reg_a, false),
+                0xde => self.bne_dp_x(),
+                0xdf => self.daa(),
+
+                0xe0 => self.clv(),
+                0xe1 => self.jst(opcode),
+                0xe2 => self.set_bit(opcode),
+                0xe3 => self.branch_bit(opcode),
+                0xe4 => read_dp!(ld, self.reg_a),
+                0xe5 => read_addr!(ld, self.reg_a),
+                0xe6 => read_i_x!(ld),
+                0xe7 => read_i_dp_x!(ld),
+                0xe8 => read_const!(ld, self.reg_a),
+                0xe9 => read_addr!(ld, self.reg_x),
+                0xea => self.set_addr_bit(opcode),
+                0xeb => read_dp!(ld, self.reg_y),
+                0xec => read_addr!(ld, self.reg_y),
+                0xed => self.cmc(),
+                0xee => pull!(self.reg_y),
+                0xef => self.sleep_stop(),
+
+                0xf0 => {
+                    let psw_z = self.psw_z;
+                    self.branch(psw_z);
+                }
+                0xf1 => self.jst(opcode),
+                0xf2 => self.set_bit(opcode),
+                0xf3 => self.branch_bit(opcode),
+                0xf4 => read_dp_i!(ld, self.reg_a, self.reg_x),
+                0xf5 => read_addr_i!(ld, self.reg_x),
+                0xf6 => read_addr_i!(ld, self.reg_y),
+                0xf7 => read_i_dp_y!(ld),
+                0xf8 => read_dp!(ld, self.reg_x),
+                0xf9 => read_dp_i!(ld, self.reg_x, self.reg_y),
+                0xfa => write_dp_dp!(st, false, true),
+                0xfb => read_dp_i!(ld, self.reg_y, self.reg_x),
+                0xfc => adjust!(inc, self.reg_y),
+                0xfd => transfer!(self.reg_a, self.reg_y, false),
+                0xfe => self.bne_y_dec(),
+                0xff => self.sleep_stop(),
             }
+        } else {
+            self.cycles(2);
+        }
+
+        self.run_cycle_count - start_cycle_count
+    }
+}
+
+impl Default for Smp {
+    fn default() -> Smp {
+        Smp::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const C: u8 = 0x01;
+    const Z: u8 = 0x02;
+    const H: u8 = 0x08;
+    const V: u8 = 0x40;
+    const N: u8 = 0x80;
+
+    // Runs a single one-byte instruction and returns the registers afterwards
+    fn execute(opcode: u8, regs: SmpRegisters) -> SmpRegisters {
+        let mut apu = Apu::new();
+        apu.write_ram(0x0200, opcode);
+        apu.smp.set_registers(SmpRegisters { pc: 0x0200, ..regs });
+        apu.step();
+        apu.smp.registers()
+    }
+
+    fn registers(reg_a: u8, reg_x: u8, reg_y: u8, flags: u8) -> SmpRegisters {
+        SmpRegisters {
+            pc: 0,
+            a: reg_a,
+            x: reg_x,
+            y: reg_y,
+            sp: 0xef,
+            psw: flags,
         }
+    }
+
+    #[test]
+    fn stops_on_breakpoints() {
+        let mut apu = Apu::new();
+        // Inside the IPL ROM's loop clearing the zero page
+        apu.smp.add_breakpoint(0xffc6);
 
-        self.cycle_count
+        assert!(apu.run(1000) < 1000);
+        assert_eq!(apu.smp.reg_pc, 0xffc6);
+        assert_eq!(
+            apu.smp.break_reason(),
+            Some(BreakReason::Breakpoint(0xffc6))
+        );
+        let x = apu.smp.reg_x;
+
+        // Running again steps past it, and stops on the next time round the loop
+        apu.run(1000);
+        assert_eq!(apu.smp.reg_pc, 0xffc6);
+        assert_eq!(apu.smp.reg_x, x.wrapping_sub(1));
+
+        apu.smp.remove_breakpoint(0xffc6);
+        assert!(apu.run(1000) >= 1000);
+        assert_eq!(apu.smp.break_reason(), None);
+    }
+
+    #[test]
+    fn stops_on_watchpoints() {
+        let mut apu = Apu::new();
+        apu.smp.add_write_watchpoint(0x0042);
+        apu.run(10000);
+        assert_eq!(
+            apu.smp.break_reason(),
+            Some(BreakReason::WriteWatchpoint(0x0042))
+        );
+        apu.smp.remove_write_watchpoint(0x0042);
+
+        apu.smp.add_read_watchpoint(0x00f4);
+        apu.run(10000);
+        assert_eq!(
+            apu.smp.break_reason(),
+            Some(BreakReason::ReadWatchpoint(0x00f4))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn traces_every_instruction() {
+        use std::sync::{Arc, Mutex};
+
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let mut apu = Apu::new();
+        let log = traced.clone();
+        apu.smp
+            .set_trace_callback(Box::new(move |pc, opcode, regs| {
+                assert_eq!(regs.pc, pc);
+                log.lock().unwrap().push((pc, opcode));
+            }));
+
+        for _ in 0..5 {
+            apu.step();
+        }
+        // The start of the IPL ROM: MOV X,#$EF; MOV SP,X; MOV A,#$00; MOV (X),A; DEC X
+        assert_eq!(
+            *traced.lock().unwrap(),
+            [
+                (0xffc0, 0xcd),
+                (0xffc2, 0xbd),
+                (0xffc3, 0xe8),
+                (0xffc5, 0xc6),
+                (0xffc6, 0x1d)
+            ]
+        );
+
+        // Over a whole cycle budget, it fires once for each instruction run
+        traced.lock().unwrap().clear();
+        let cycles = apu.run(1000);
+        let mut control = Apu::new();
+        for _ in 0..5 {
+            control.step();
+        }
+        let mut instructions = 0;
+        let mut control_cycles = 0;
+        while control_cycles < cycles {
+            control_cycles += control.step();
+            instructions += 1;
+        }
+        assert_eq!(control_cycles, cycles);
+        assert_eq!(traced.lock().unwrap().len(), instructions);
+
+        apu.smp.clear_trace_callback();
+        apu.step();
+        assert_eq!(traced.lock().unwrap().len(), instructions);
+    }
+
+    #[test]
+    fn steps_through_the_ipl_rom() {
+        let mut apu = Apu::new();
+        // MOV X,#$EF; MOV SP,X; MOV A,#$00; then the first time round the clearing loop
+        let expected = [
+            (0xffc0, 2),
+            (0xffc2, 2),
+            (0xffc3, 2),
+            (0xffc5, 4),
+            (0xffc6, 2),
+        ];
+        for &(pc, cycles) in expected.iter() {
+            assert_eq!(apu.smp.reg_pc, pc);
+            assert_eq!(apu.step(), cycles);
+        }
+        // A taken branch costs two more cycles than one that falls through
+        assert_eq!(apu.step(), 4);
+        assert_eq!(apu.smp.reg_pc, 0xffc5);
+
+        while apu.smp.reg_pc != 0xffc7 || apu.smp.reg_x != 0 {
+            apu.step();
+        }
+        assert_eq!(apu.step(), 2);
+        // MOV $F4,#$AA
+        assert_eq!(apu.smp.reg_pc, 0xffc9);
+        assert_eq!(apu.step(), 5);
+        assert_eq!(apu.read_port(0), 0xaa);
+    }
+
+    #[test]
+    fn halts_on_stop_and_sleep() {
+        for &opcode in [0xef, 0xff].iter() {
+            let mut apu = Apu::new();
+            apu.write_ram(0x0200, opcode);
+            apu.smp.reg_pc = 0x0200;
+            assert!(!apu.smp.is_halted());
+            apu.step();
+            assert!(apu.smp.is_halted());
+
+            // The rest of the APU keeps running for the whole budget
+            let buffered = apu.dsp.output_buffer.len();
+            assert_eq!(apu.run(6400), 6400);
+            assert!(apu.smp.is_halted());
+            assert_eq!(apu.smp.reg_pc, 0x0201);
+            assert!(apu.dsp.output_buffer.len() - buffered >= 99);
+        }
+    }
+
+    #[test]
+    fn rendering_runs_past_breakpoints() {
+        let mut apu = Apu::new();
+        apu.smp.add_breakpoint(0xffc6);
+        let mut buffer = [(0, 0); 64];
+        apu.render(&mut buffer);
+        assert_eq!(apu.samples_rendered(), 64);
+        assert!(apu.smp.cycle_count() >= 64 * 64);
+    }
+
+    #[test]
+    fn decimal_adjusts() {
+        // (opcode, A, PSW in, A out, PSW out)
+        let cases = [
+            // DAA
+            (0xdf, 0x45, 0, 0x45, 0),
+            (0xdf, 0x0f, 0, 0x15, 0),
+            (0xdf, 0x7a, 0, 0x80, N),
+            (0xdf, 0x9a, 0, 0x00, Z | C),
+            (0xdf, 0xa5, 0, 0x05, C),
+            (0xdf, 0x12, C, 0x72, C),
+            (0xdf, 0x10, H, 0x16, H),
+            (0xdf, 0x00, C | H, 0x66, C | H),
+            // DAS
+            (0xbe, 0x15, C | H, 0x15, C | H),
+            (0xbe, 0x0f, C | H, 0x09, C | H),
+            (0xbe, 0x9a, C | H, 0x34, H),
+            (0xbe, 0xf0, H, 0x90, N | H),
+            (0xbe, 0x66, 0, 0x00, Z),
+            (0xbe, 0x47, C, 0x41, C),
+        ];
+        for &(opcode, a, psw, expected_a, expected_psw) in cases.iter() {
+            let regs = execute(opcode, registers(a, 0, 0, psw));
+            assert_eq!(
+                (regs.a, regs.psw),
+                (expected_a, expected_psw),
+                "opcode {:02x} with A={:02x} PSW={:02x}",
+                opcode,
+                a,
+                psw
+            );
+        }
+    }
+
+    #[test]
+    fn divides() {
+        // (YA, X, A out, Y out, PSW out)
+        let cases = [
+            (0x1234, 0x56, 0x36, 0x10, 0),
+            (0x0005, 0x10, 0x00, 0x05, Z | H),
+            // A 9-bit quotient sets V and loses its top bit
+            (0x1800, 0x10, 0x80, 0x00, N | V | H),
+            // Quotients past 9 bits, including X = 0, give the divider's fallback result
+            (0x4000, 0x10, 0xdd, 0x30, N | V | H),
+            (0x1234, 0x00, 0xed, 0x34, N | V | H),
+            (0x0000, 0x00, 0xff, 0x00, N | V | H),
+            // H compares the low nibbles of Y and X, independently of V
+            (0x0fff, 0x20, 0x7f, 0x1f, H),
+            (0x2000, 0x1f, 0x08, 0x08, V),
+        ];
+        for &(ya, x, expected_a, expected_y, expected_psw) in cases.iter() {
+            let regs = execute(0x9e, registers(ya as u8, x, (ya >> 8) as u8, 0));
+            assert_eq!(
+                (regs.a, regs.y, regs.psw),
+                (expected_a, expected_y, expected_psw),
+                "YA={:04x} X={:02x}",
+                ya,
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn compare_sets_the_flags() {
+        // (A, operand, flags out); V and H are left alone
+        let cases = [
+            (0x40, 0x40, V | H | Z | C),
+            (0x50, 0x40, V | H | C),
+            (0x30, 0x40, V | H | N),
+            (0x00, 0x01, V | H | N),
+            (0x80, 0x01, V | H | C),
+        ];
+        for &(reg_a, operand, flags) in cases.iter() {
+            let mut apu = Apu::new();
+            // CMP A,#operand
+            apu.write_ram(0x0200, 0x68);
+            apu.write_ram(0x0201, operand);
+            let regs = registers(reg_a, 0, 0, V | H | N | Z);
+            apu.smp.set_registers(SmpRegisters { pc: 0x0200, ..regs });
+            apu.step();
+
+            assert_eq!(apu.smp.get_psw(), flags);
+            assert_eq!(apu.smp.flag_carry(), flags & C != 0);
+            assert_eq!(apu.smp.flag_zero(), flags & Z != 0);
+            assert_eq!(apu.smp.flag_negative(), flags & N != 0);
+            assert!(apu.smp.flag_overflow() && apu.smp.flag_half_carry());
+            assert_eq!(apu.smp.reg_a, reg_a);
+        }
+    }
+
+    #[test]
+    fn direct_page_follows_the_p_flag() {
+        // MOV $42,A; MOV $F0+X,A
+        let program = [0xc4, 0x42, 0xd4, 0xf0];
+        for &high in [false, true].iter() {
+            let mut apu = Apu::new();
+            for (i, &byte) in program.iter().enumerate() {
+                apu.write_ram(0x0200 + i as u16, byte);
+            }
+            let regs = registers(0x5a, 0x20, 0, 0);
+            apu.smp.set_registers(SmpRegisters { pc: 0x0200, ..regs });
+            apu.smp.set_direct_page(high);
+            assert_eq!(apu.smp.flag_direct_page(), high);
+            apu.step();
+            apu.step();
+
+            // dp+X wraps within the page rather than carrying into the next
+            let page = if high { 0x0100 } else { 0x0000 };
+            let other_page = page ^ 0x0100;
+            assert_eq!(apu.read_ram(page | 0x42), 0x5a);
+            assert_eq!(apu.read_ram(page | 0x10), 0x5a);
+            assert_eq!(apu.read_ram(other_page | 0x42), 0);
+            assert_eq!(apu.read_ram(other_page | 0x10), 0);
+            assert_eq!(apu.read_ram(0x0210), 0);
+        }
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let mut apu = Apu::new();
+        // Every PSW bit pattern survives being unpacked into the flags and packed again
+        for psw in 0..=0xff {
+            let regs = SmpRegisters {
+                pc: 0x1234 ^ psw as u16,
+                a: psw,
+                x: !psw,
+                y: psw.rotate_left(3),
+                sp: psw ^ 0x5a,
+                psw,
+            };
+            apu.smp.set_registers(regs);
+            assert_eq!(apu.smp.registers(), regs);
+            assert_eq!(apu.smp.reg_pc, regs.pc);
+            assert_eq!(apu.smp.flag_carry(), psw & C != 0);
+            assert_eq!(apu.smp.flag_negative(), psw & N != 0);
+        }
+    }
+
+    #[test]
+    fn run_reports_the_overshoot() {
+        // DIV YA,X is the longest instruction, at 12 cycles
+        const MAX_INSTRUCTION_CYCLES: usize = 12;
+        let mut apu = Apu::new();
+        for address in 0x0200..0x0240 {
+            apu.write_ram(address, 0x9e);
+        }
+        apu.smp.reg_pc = 0x0200;
+        assert_eq!(apu.run(100), 108);
+
+        // ...and from the IPL ROM's mix of shorter instructions
+        let mut apu = Apu::new();
+        for _ in 0..10 {
+            let before = apu.smp.cycle_count();
+            let cycles = apu.run(100);
+            assert!((100..100 + MAX_INSTRUCTION_CYCLES).contains(&cycles));
+            assert_eq!(apu.smp.cycle_count() - before, cycles as u64);
+        }
+    }
+
+    #[test]
+    fn counts_cycles_between_breakpoints() {
+        let mut apu = Apu::new();
+        let program = [
+            0xe8, 0x12, // MOV A,#$12: 2 cycles
+            0x5d, // MOV X,A: 2
+            0x3d, // INC X: 2
+            0xc4, 0x10, // MOV $10,A: 4
+            0x00, // NOP: 2
+            0x9e, // DIV YA,X: 12
+        ];
+        for (i, &byte) in program.iter().enumerate() {
+            apu.write_ram(0x0200 + i as u16, byte);
+        }
+        apu.run(1000);
+        assert!(apu.smp.cycle_count() >= 1000);
+
+        apu.smp.reg_pc = 0x0200;
+        apu.smp.reset_cycle_count();
+        apu.smp.add_breakpoint(0x0208);
+        apu.run(1000);
+        assert_eq!(apu.smp.reg_pc, 0x0208);
+        assert_eq!(apu.smp.cycle_count(), 24);
     }
 }
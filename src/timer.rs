@@ -1,4 +1,7 @@
-use std::num::NonZeroU8;
+use core::num::NonZeroU8;
+
+use super::apu::LoadStateError;
+use super::state::{StateReader, StateWriter};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timer {
@@ -13,7 +16,7 @@ pub struct Timer {
 impl Timer {
     pub fn new(resolution: usize) -> Timer {
         Timer {
-            resolution: resolution,
+            resolution,
             is_running: false,
             ticks: 0,
             target: None,
@@ -27,19 +30,26 @@ impl Timer {
             return;
         }
         self.ticks += 1;
-        if self.ticks > self.resolution {
+        if self.ticks >= self.resolution {
             self.ticks -= self.resolution;
 
-            self.counter_low += 1;
-            if let Some(target) = self.target {
-                if self.counter_low == target.into() {
-                    self.counter_high += 1;
-                    self.counter_low = 0;
-                }
+            // A target of 0 acts as a period of 256, which falls out of letting the
+            //  8-bit counter wrap around to 0.
+            self.counter_low = self.counter_low.wrapping_add(1);
+            if self.counter_low == self.target.map_or(0, |target| target.get()) {
+                // The output counter is only 4 bits wide
+                self.counter_high = (self.counter_high + 1) & 0x0f;
+                self.counter_low = 0;
             }
         }
     }
 
+    pub fn cpu_cycles_callback(&mut self, num_cycles: usize) {
+        for _ in 0..num_cycles {
+            self.tick();
+        }
+    }
+
     pub fn set_start_stop_bit(&mut self, value: bool) {
         if value && !self.is_running {
             self.ticks = 0;
@@ -52,9 +62,98 @@ impl Timer {
         self.target = NonZeroU8::new(value)
     }
 
+    /// How many 2.048MHz cycles make up one tick of the timer's internal counter.
+    pub fn resolution(&self) -> usize {
+        self.resolution
+    }
+
+    pub fn target(&self) -> Option<u8> {
+        self.target.map(|target| target.get())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    pub fn peek_counter(&self) -> u8 {
+        self.counter_high
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.is_running);
+        writer.write_u32(self.ticks as u32);
+        writer.write_u8(self.target().unwrap_or(0));
+        writer.write_u8(self.counter_low);
+        writer.write_u8(self.counter_high);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), LoadStateError> {
+        self.is_running = reader.read_bool()?;
+        self.ticks = reader.read_u32()? as usize;
+        self.set_target(reader.read_u8()?);
+        self.counter_low = reader.read_u8()?;
+        self.counter_high = reader.read_u8()? & 0x0f;
+        Ok(())
+    }
+
     pub fn read_counter(&mut self) -> u8 {
-        let ret = self.counter_high & 0x0f;
+        let ret = self.counter_high;
         self.counter_high = 0;
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running_timer(resolution: usize, target: u8) -> Timer {
+        let mut timer = Timer::new(resolution);
+        timer.set_target(target);
+        timer.set_start_stop_bit(true);
+        timer
+    }
+
+    #[test]
+    fn zero_target_counts_to_256() {
+        let mut timer = running_timer(1, 0);
+        for _ in 0..255 {
+            timer.tick();
+        }
+        assert_eq!(timer.peek_counter(), 0);
+        timer.tick();
+        assert_eq!(timer.peek_counter(), 1);
+
+        // Well past the point a non-wrapping counter would have overflowed
+        timer.cpu_cycles_callback(256 * 4);
+        assert_eq!(timer.peek_counter(), 5);
+        assert_eq!(timer.target(), None);
+    }
+
+    #[test]
+    fn stages_take_exactly_resolution_ticks() {
+        let mut timer = running_timer(128, 1);
+        timer.cpu_cycles_callback(128 * 5);
+        assert_eq!(timer.peek_counter(), 5);
+        timer.cpu_cycles_callback(127);
+        assert_eq!(timer.peek_counter(), 5);
+        timer.cpu_cycles_callback(1);
+        assert_eq!(timer.peek_counter(), 6);
+
+        // Reading clears the output counter without disturbing the stage in progress
+        timer.cpu_cycles_callback(64);
+        assert_eq!(timer.read_counter(), 6);
+        timer.cpu_cycles_callback(64);
+        assert_eq!(timer.peek_counter(), 1);
+    }
+
+    #[test]
+    fn counter_wraps_at_4_bits() {
+        for &n in [16, 17, 31, 40].iter() {
+            let mut timer = running_timer(1, 1);
+            timer.cpu_cycles_callback(n);
+            assert_eq!(timer.read_counter(), (n & 0x0f) as u8);
+            assert_eq!(timer.read_counter(), 0);
+        }
+    }
+}
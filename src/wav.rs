@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use super::apu::Apu;
+use super::dsp::dsp::BUFFER_LEN;
+
+const HEADER_LEN: u32 = 36;
+const NUM_CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const BLOCK_ALIGN: u16 = NUM_CHANNELS * BITS_PER_SAMPLE / 8;
+
+impl Apu {
+    /// Renders `seconds` of audio into a 16-bit stereo PCM WAV file at the current output
+    /// sample rate. Fails without creating the file if the audio wouldn't fit in a WAV
+    /// file's 4 GiB limit.
+    pub fn render_to_wav<P: AsRef<Path>>(&mut self, path: P, seconds: f32) -> Result<()> {
+        let num_frames = (seconds * (self.output_sample_rate() as f32)) as usize;
+        let data_len = wav_data_len(num_frames)?;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_wav(&mut writer, num_frames, data_len)?;
+        writer.flush()
+    }
+
+    fn write_wav<W: Write>(
+        &mut self,
+        writer: &mut W,
+        num_frames: usize,
+        data_len: u32,
+    ) -> Result<()> {
+        let sample_rate = self.output_sample_rate();
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_LEN + data_len).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&NUM_CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * (BLOCK_ALIGN as u32)).to_le_bytes())?;
+        writer.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&data_len.to_le_bytes())?;
+
+        let mut buffer = vec![(0, 0); BUFFER_LEN];
+        let mut remaining = num_frames;
+        while remaining > 0 {
+            let num_samples = remaining.min(buffer.len());
+            self.render(&mut buffer[..num_samples]);
+            for &(left, right) in buffer[..num_samples].iter() {
+                writer.write_all(&left.to_le_bytes())?;
+                writer.write_all(&right.to_le_bytes())?;
+            }
+            remaining -= num_samples;
+        }
+        Ok(())
+    }
+}
+
+// The RIFF size field covers everything after it, so the data has to leave room for the
+//  rest of the header within 32 bits.
+fn wav_data_len(num_frames: usize) -> Result<u32> {
+    u32::try_from(num_frames)
+        .ok()
+        .and_then(|num_frames| num_frames.checked_mul(BLOCK_ALIGN as u32))
+        .filter(|data_len| data_len.checked_add(HEADER_LEN).is_some())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "too long for a WAV file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_pcm_header() {
+        let mut apu = Apu::new();
+        apu.set_output_sample_rate(44100);
+        let mut wav = Vec::new();
+        apu.write_wav(&mut wav, 100, wav_data_len(100).unwrap())
+            .unwrap();
+
+        assert_eq!(wav.len(), 44 + 400);
+        let u16_at = |pos: usize| u16::from_le_bytes([wav[pos], wav[pos + 1]]);
+        let u32_at =
+            |pos: usize| u32::from_le_bytes([wav[pos], wav[pos + 1], wav[pos + 2], wav[pos + 3]]);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 400);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(16), 16);
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), 2);
+        assert_eq!(u32_at(24), 44100);
+        assert_eq!(u32_at(28), 44100 * 4);
+        assert_eq!(u16_at(32), 4);
+        assert_eq!(u16_at(34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), 400);
+    }
+
+    #[test]
+    fn rejects_files_over_4_gib() {
+        assert_eq!(wav_data_len(0x3fff_fff6).unwrap(), 0xffff_ffd8);
+        assert!(wav_data_len(0x3fff_fff7).is_err());
+        assert!(wav_data_len(usize::MAX).is_err());
+
+        let path = std::env::temp_dir().join("snes_apu_rejects_files_over_4_gib.wav");
+        let err = Apu::new().render_to_wav(&path, 1.0e9).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+}